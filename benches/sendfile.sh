@@ -0,0 +1,74 @@
+#!/bin/sh
+# Compare buffered and sendfile downloads of large files with a cold page cache.
+#
+#   benches/sendfile.sh [dir] [size in MiB] [files]
+#
+# The files are random data, written once and kept in dir for later runs.
+# Each run evicts them from the page cache first, which needs GNU dd.
+# While they download, a small file is fetched over and over, and its
+# slowest time shows whether disk reads held up the runtime's workers.
+
+set -eu
+
+dir=${1:-target/bench-sendfile}
+size=${2:-4096}
+files=${3:-2}
+port=18480
+root=$(cd "$(dirname "$0")/.." && pwd)
+
+cd "$root"
+cargo +nightly build --release --quiet
+mkdir -p "$dir"
+
+i=0
+while [ "$i" -lt "$files" ]; do
+    f="$dir/big$i"
+    if [ ! -f "$f" ] || [ "$(stat -c %s "$f")" -ne $((size << 20)) ]; then
+        echo "writing $f"
+        dd if=/dev/urandom of="$f" bs=1M count="$size" status=none
+    fi
+    i=$((i + 1))
+done
+echo small > "$dir/small"
+
+run() {
+    name=$1
+    shift
+
+    i=0
+    while [ "$i" -lt "$files" ]; do
+        dd if="$dir/big$i" iflag=nocache count=0 status=none
+        i=$((i + 1))
+    done
+
+    target/release/webdir -b "127.0.0.1:$port" -r "$dir" "$@" >/dev/null 2>&1 &
+    pid=$!
+    sleep 0.5
+
+    start=$(date +%s.%N)
+    i=0
+    pids=
+    while [ "$i" -lt "$files" ]; do
+        curl -s -o /dev/null "http://127.0.0.1:$port/big$i" &
+        pids="$pids $!"
+        i=$((i + 1))
+    done
+
+    slowest=0
+    while kill -0 $pids 2>/dev/null; do
+        t=$(curl -s -o /dev/null -w '%{time_total}' "http://127.0.0.1:$port/small")
+        slowest=$(echo "$t $slowest" | awk '{ print ($1 > $2) ? $1 : $2 }')
+        sleep 0.05
+    done
+    wait $pids
+    end=$(date +%s.%N)
+
+    kill "$pid"
+    wait "$pid" 2>/dev/null || true
+
+    echo "$start $end $size $files $slowest" | awk -v name="$name" \
+        '{ printf "%-9s %7.1f MiB/s   slowest small file %.3fs\n", name, $3 * $4 / ($2 - $1), $5 }'
+}
+
+run buffered --no-sendfile
+run sendfile
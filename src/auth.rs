@@ -0,0 +1,327 @@
+use std::{ fs, io };
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::path::{ Path, PathBuf };
+use tokio::task::block_in_place;
+use http::{ HeaderMap, HeaderValue };
+use headers::HeaderMapExt;
+use headers::authorization::{ Authorization, Basic };
+use data_encoding::BASE64;
+use ring::digest;
+use crate::utils::path_canonicalize;
+use crate::tls::ClientCert;
+
+
+/// How long a verified password is trusted without running bcrypt again.
+const VERIFIED_TTL: Duration = Duration::from_secs(60);
+const VERIFIED_MAX: usize = 1 << 10;
+
+/// Users of an Apache-style htpasswd file.
+///
+/// Only bcrypt (`$2y$`) and SHA-1 (`{SHA}`) entries are supported.
+pub struct Htpasswd {
+    users: HashMap<String, String>,
+
+    /// checked for unknown users, so they take as long as known ones.
+    dummy: String,
+
+    /// recent successes by user and password digest.
+    verified: Mutex<HashMap<(String, Vec<u8>), Instant>>
+}
+
+impl Htpasswd {
+    pub fn open(path: &Path) -> io::Result<Htpasswd> {
+        let content = fs::read_to_string(path)?;
+        let mut users = HashMap::new();
+
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+
+            match line.split_once(':') {
+                Some((user, hash)) if is_supported(hash) => {
+                    users.insert(user.to_owned(), hash.to_owned());
+                },
+                Some((user, _)) => warn!(?path, %user, "htpasswd/unsupported hash"),
+                None => warn!(?path, %line, "htpasswd/bad line")
+            }
+        }
+
+        // as costly as the first bcrypt entry
+        let cost = users.values()
+            .filter(|hash| hash.starts_with('$'))
+            .find_map(|hash| hash.get(4..6)?.parse::<u32>().ok());
+        let dummy = match cost {
+            Some(cost) => bcrypt::hash("", cost).map_err(io::Error::other)?,
+            None => format!("{{SHA}}{}", BASE64.encode(digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, b"").as_ref()))
+        };
+
+        Ok(Htpasswd { users, dummy, verified: Mutex::new(HashMap::new()) })
+    }
+
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        let key = (user.to_owned(), digest::digest(&digest::SHA256, password.as_bytes()).as_ref().to_vec());
+        let now = Instant::now();
+
+        if let Some(&at) = self.verified.lock().unwrap().get(&key) {
+            if now.duration_since(at) < VERIFIED_TTL {
+                return true;
+            }
+        }
+
+        let ok = match self.users.get(user) {
+            Some(hash) => check(hash, password),
+            None => {
+                check(&self.dummy, password);
+                false
+            }
+        };
+
+        if ok {
+            let mut verified = self.verified.lock().unwrap();
+            if verified.len() >= VERIFIED_MAX {
+                verified.retain(|_, at| now.duration_since(*at) < VERIFIED_TTL);
+            }
+            if verified.len() < VERIFIED_MAX {
+                verified.insert(key, now);
+            }
+        }
+
+        ok
+    }
+}
+
+fn check(hash: &str, password: &str) -> bool {
+    if let Some(sha) = hash.strip_prefix("{SHA}") {
+        let output = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, password.as_bytes());
+        let output = BASE64.encode(output.as_ref());
+        output.len() == sha.len() && output.bytes()
+            .zip(sha.bytes())
+            .fold(0, |sum, (x, y)| sum | (x ^ y)) == 0
+    } else {
+        block_in_place(|| bcrypt::verify(password, hash).unwrap_or(false))
+    }
+}
+
+fn is_supported(hash: &str) -> bool {
+    hash.starts_with("{SHA}")
+        || ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix))
+}
+
+pub struct Realm {
+    pub name: String,
+    pub users: Htpasswd
+}
+
+impl Realm {
+    pub fn challenge(&self) -> HeaderValue {
+        let name = self.name.replace('\\', "\\\\").replace('"', "\\\"");
+        HeaderValue::from_str(&format!("Basic realm=\"{}\", charset=\"UTF-8\"", name))
+            .unwrap_or_else(|_| HeaderValue::from_static("Basic"))
+    }
+}
+
+/// Per-path access rules, the longest matching prefix wins.
+///
+/// A path that matches no rule is public.
+/// Client certificate rules are checked before Basic auth.
+#[derive(Default)]
+pub struct Auth {
+    rules: Vec<(PathBuf, Option<Arc<Realm>>)>,
+    cert_rules: Vec<(PathBuf, Vec<String>)>
+}
+
+pub enum Access {
+    Public,
+    User(String),
+    Denied(Arc<Realm>),
+    Forbidden
+}
+
+impl Auth {
+    pub fn protect(&mut self, prefix: &str, realm: Arc<Realm>) {
+        self.insert(prefix, Some(realm));
+    }
+
+    pub fn public(&mut self, prefix: &str) {
+        self.insert(prefix, None);
+    }
+
+    /// Only clients whose certificate subject or common name is `name`
+    /// may access `prefix`, can be repeated to allow several names.
+    pub fn allow_cert(&mut self, prefix: &str, name: &str) {
+        let (_, prefix) = path_canonicalize(Path::new("/"), prefix);
+
+        if let Some((_, names)) = self.cert_rules.iter_mut().find(|(p, _)| *p == prefix) {
+            names.push(name.to_owned());
+        } else {
+            self.cert_rules.push((prefix, vec![name.to_owned()]));
+            self.cert_rules.sort_by_key(|(p, _)| Reverse(p.components().count()));
+        }
+    }
+
+    fn insert(&mut self, prefix: &str, realm: Option<Arc<Realm>>) {
+        let (_, prefix) = path_canonicalize(Path::new("/"), prefix);
+        self.rules.retain(|(p, _)| *p != prefix);
+        self.rules.push((prefix, realm));
+        self.rules.sort_by_key(|(p, _)| Reverse(p.components().count()));
+    }
+
+    /// `path` is the decoded request path, `resolved` where it leads after symlinks,
+    /// both have to pass.
+    pub fn check(&self, path: &Path, resolved: &Path, map: &HeaderMap, cert: Option<&ClientCert>) -> Access {
+        let (_, path) = path_canonicalize(Path::new("/"), path);
+        let (_, resolved) = path_canonicalize(Path::new("/"), resolved);
+        let paths = [path, resolved];
+
+        for path in &paths {
+            if let Some((_, names)) = self.cert_rules.iter().find(|(prefix, _)| path.starts_with(prefix)) {
+                match cert {
+                    Some(cert) if names.iter().any(|name| cert.is(name)) => (),
+                    _ => return Access::Forbidden
+                }
+            }
+        }
+
+        let mut realms = paths.iter()
+            .filter_map(|path| match self.rules.iter().find(|(prefix, _)| path.starts_with(prefix)) {
+                Some((_, realm)) => realm.as_ref(),
+                None => None
+            })
+            .collect::<Vec<_>>();
+        realms.dedup_by(|x, y| Arc::ptr_eq(x, y));
+
+        let realm = match realms.first() {
+            Some(realm) => realm,
+            None => return Access::Public
+        };
+
+        match map.typed_get::<Authorization<Basic>>() {
+            Some(auth) => match realms.iter().find(|realm| !realm.users.verify(auth.username(), auth.password())) {
+                Some(realm) => Access::Denied((*realm).clone()),
+                None => Access::User(auth.username().to_owned())
+            },
+            None => Access::Denied((*realm).clone())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ env, process };
+    use http::header::AUTHORIZATION;
+    use super::*;
+
+    fn htpasswd(name: &str, content: &str) -> Htpasswd {
+        let path = env::temp_dir().join(format!("webdir-htpasswd-{}-{}", name, process::id()));
+        fs::write(&path, content).unwrap();
+        let users = Htpasswd::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        users
+    }
+
+    fn basic(user: &str, password: &str) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        let value = format!("Basic {}", BASE64.encode(format!("{}:{}", user, password).as_bytes()));
+        map.insert(AUTHORIZATION, HeaderValue::from_str(&value).unwrap());
+        map
+    }
+
+    fn realm(name: &str) -> Arc<Realm> {
+        let alice = bcrypt::hash("secret", 4).unwrap();
+        Arc::new(Realm { name: name.into(), users: htpasswd(name, &format!("alice:{}\n", alice)) })
+    }
+
+    fn cert(name: &str) -> ClientCert {
+        ClientCert { subject: format!("CN={}", name), common_name: Some(name.into()) }
+    }
+
+    #[test]
+    fn htpasswd_entries() {
+        let sha = BASE64.encode(digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, b"hunter2").as_ref());
+        let content = format!(
+            "# users\n\nalice:{}\nbob:{{SHA}}{}\ncarol:$apr1$salt$hash\nno colon here\n",
+            bcrypt::hash("secret", 4).unwrap(),
+            sha
+        );
+        let users = htpasswd("entries", &content);
+
+        assert!(users.verify("alice", "secret"));
+        assert!(users.verify("alice", "secret"));
+        assert!(!users.verify("alice", "wrong"));
+        assert!(users.verify("bob", "hunter2"));
+        assert!(!users.verify("bob", "hunter"));
+
+        // skipped, so nobody gets in as them
+        assert!(!users.users.contains_key("carol"));
+        assert!(!users.verify("carol", "salt"));
+        assert!(!users.verify("no colon here", ""));
+
+        assert!(!users.verify("mallory", "secret"));
+        assert!(!users.verify("mallory", ""));
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let mut auth = Auth::default();
+        auth.protect("/private", realm("private"));
+        auth.public("private/open/");
+        auth.protect("/private/open/deep", realm("deep"));
+
+        let check = |path: &str, map: &HeaderMap| auth.check(Path::new(path), Path::new(path), map, None);
+        let none = HeaderMap::new();
+
+        assert!(matches!(check("/", &none), Access::Public));
+        assert!(matches!(check("/privateer", &none), Access::Public));
+        assert!(matches!(check("/private/file", &none), Access::Denied(realm) if realm.name == "private"));
+        assert!(matches!(check("/private/file", &basic("alice", "wrong")), Access::Denied(_)));
+        assert!(matches!(check("/private/file", &basic("alice", "secret")), Access::User(user) if user == "alice"));
+        assert!(matches!(check("/private/open/file", &none), Access::Public));
+        assert!(matches!(check("/private/open/../file", &none), Access::Denied(_)));
+        assert!(matches!(check("/private/open/deep/file", &none), Access::Denied(realm) if realm.name == "deep"));
+    }
+
+    #[test]
+    fn resolved_path_checked_too() {
+        let mut auth = Auth::default();
+        auth.protect("/private", realm("resolved"));
+        auth.allow_cert("/certs", "alice");
+        let none = HeaderMap::new();
+
+        // a public symlink leading to protected files
+        let access = auth.check(Path::new("/pub/link"), Path::new("/private/file"), &none, None);
+        assert!(matches!(access, Access::Denied(_)));
+        let access = auth.check(Path::new("/pub/link"), Path::new("/private/file"), &basic("alice", "secret"), None);
+        assert!(matches!(access, Access::User(_)));
+
+        let access = auth.check(Path::new("/pub/link"), Path::new("/certs/file"), &none, None);
+        assert!(matches!(access, Access::Forbidden));
+    }
+
+    #[test]
+    fn cert_rules() {
+        let mut auth = Auth::default();
+        auth.allow_cert("/certs", "alice");
+        auth.allow_cert("/certs", "CN=bob");
+        auth.protect("/certs/basic", realm("cert"));
+        let none = HeaderMap::new();
+
+        let check = |path: &str, map: &HeaderMap, cert: Option<&ClientCert>| {
+            auth.check(Path::new(path), Path::new(path), map, cert)
+        };
+
+        assert!(matches!(check("/certs/file", &none, None), Access::Forbidden));
+        assert!(matches!(check("/certs/file", &none, Some(&cert("mallory"))), Access::Forbidden));
+        assert!(matches!(check("/certs/file", &none, Some(&cert("alice"))), Access::Public));
+        assert!(matches!(check("/certs/file", &none, Some(&cert("bob"))), Access::Public));
+        assert!(matches!(check("/other", &none, None), Access::Public));
+
+        // a certificate doesn't stand in for the password, nor the other way round
+        assert!(matches!(check("/certs/basic/file", &none, Some(&cert("alice"))), Access::Denied(_)));
+        assert!(matches!(check("/certs/basic/file", &basic("alice", "secret"), None), Access::Forbidden));
+    }
+}
@@ -1,120 +1,1380 @@
-use std::{ fs, env };
-use std::io::Cursor;
+use std::{ fs, env, io, cmp };
+use std::str::FromStr;
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::net::SocketAddr;
 use std::path::{ Path, PathBuf };
 use argh::FromArgs;
 use anyhow::Context;
-use futures::future::TryFutureExt;
-use tokio::net::TcpListener;
+use std::time::Duration;
+use futures::future::{ self, TryFutureExt };
+use tokio::time;
+use tokio::sync::watch;
+#[cfg(unix)]
+use tokio::signal::unix::{ signal, SignalKind };
 use tokio_rustls::TlsAcceptor;
 use tokio_rustls::rustls::ServerConfig;
-use tokio_rustls::rustls::pki_types::{ CertificateDer, PrivateKeyDer };
 use hyper_util::server::conn::auto::Builder as HttpBuilder;
-use tracing::{ info, error };
-use webdir::{ WebDir, WebStream };
+use hyper_util::server::graceful::GracefulShutdown;
+use tracing::{ info, info_span, debug, warn, error, Instrument };
+use webdir::{
+    tls, WebDir, WebStream, Peer, SymlinkPolicy, Filter, Auth, Realm, Htpasswd, Redirect,
+    Addr, Listener, ProxyProtocol, ProxyStream, Proxy, TrustedProxy, TrustedProxies,
+    Timeout, Timeouts, TimeoutStream, InFlight, Limits, Socket, Bucket, Throttle, Compression,
+    Sendfile, SendfileStream
+};
+#[cfg(unix)]
+use std::os::unix::io::{ AsRawFd, RawFd };
+#[cfg(unix)]
+use webdir::{ systemd, upgrade };
 
 
 /// WebDir -- simple web file server
 #[derive(FromArgs)]
 struct Options {
-    /// bind address
+    /// listen address, can be repeated: <addr>|unix:<path>[,tls][,plain][,proxy[=optional]][,mode=<octal>][,owner=<user>[:<group>]], optional takes the PROXY header from --trusted-proxy only
     #[argh(option, short = 'b')]
-    pub bind: SocketAddr,
+    pub bind: Vec<Bind>,
 
     /// root path
     #[argh(option, short = 'r')]
     pub root: Option<PathBuf>,
 
+    /// serve the root below this URL path, such as /files
+    #[argh(option)]
+    pub base_path: Option<BasePath>,
+
+    /// believe Forwarded and X-Forwarded-* headers from this address or network, or unix
+    #[argh(option)]
+    pub trusted_proxy: Vec<TrustedProxy>,
+
     /// index
     #[argh(switch, short = 'i')]
     pub index: bool,
 
-    /// enable HTTPS
+    /// symlink policy: follow, contained or never (default: contained, which no longer serves symlinks leading out of the root)
+    #[argh(option, default = "SymlinkPolicy::default()")]
+    pub symlink: SymlinkPolicy,
+
+    /// show dotfiles
+    #[argh(switch)]
+    pub show_hidden: bool,
+
+    /// hide paths matching this gitignore-style glob
+    #[argh(option)]
+    pub ignore: Vec<String>,
+
+    /// protect a path of the default root with Basic auth, a vhost takes its own auth= options: <prefix>=<realm>:<htpasswd file>
+    #[argh(option)]
+    pub auth: Vec<AuthRule>,
+
+    /// make a path of the default root public, overriding --auth on a parent
+    #[argh(option)]
+    pub public: Vec<String>,
+
+    /// serve foo.br, foo.zst or foo.gz for foo to clients that accept the encoding
+    #[argh(switch)]
+    pub precompressed: bool,
+
+    /// virtual host, such as example.com or *.example.com: <name>=<root>[,index][,noindex][,symlink=<policy>][,show-hidden][,auth=<prefix>=<realm>:<htpasswd file>][,public=<prefix>][,cert-allow=<prefix>=<name>]
+    #[argh(option)]
+    pub vhost: Vec<VHost>,
+
+    /// answer unknown hosts with 404 instead of serving the default root
+    #[argh(switch)]
+    pub strict_hosts: bool,
+
+    /// only allow clients with this certificate subject or CN to a path of the default root: <prefix>=<name>
+    #[argh(option)]
+    pub cert_allow: Vec<String>,
+
+    /// enable HTTPS with a PEM file holding both certificate chain and private key
+    #[argh(option)]
+    pub https: Option<PathBuf>,
+
+    /// enable HTTPS with this certificate chain, leaf first
     #[argh(option)]
-    pub https: Option<PathBuf>
+    pub tls_cert: Option<PathBuf>,
+
+    /// private key for --tls-cert, may be an encrypted PKCS#8 key
+    #[argh(option)]
+    pub tls_key: Option<PathBuf>,
+
+    /// read the private key passphrase from this file
+    #[argh(option)]
+    pub tls_key_pass_file: Option<PathBuf>,
+
+    /// read the private key passphrase from this environment variable
+    #[argh(option)]
+    pub tls_key_pass_env: Option<String>,
+
+    /// enable HTTPS with a generated self-signed certificate for the bound addresses, or the host name and every interface address on a wildcard bind
+    #[argh(switch)]
+    pub self_signed: bool,
+
+    /// save the self-signed certificate to this directory and reuse it next run
+    #[argh(option)]
+    pub self_signed_dir: Option<PathBuf>,
+
+    /// extra DNS name or IP address for the self-signed certificate
+    #[argh(option)]
+    pub tls_san: Vec<String>,
+
+    /// serve another certificate for an SNI name: <name>=<cert>[,<key>]
+    #[argh(option)]
+    pub tls_sni: Vec<SniCert>,
+
+    /// check certificate files for changes every N seconds, 0 to only reload on SIGHUP (default: 60)
+    #[argh(option, default = "60")]
+    pub tls_reload_interval: u64,
+
+    /// verify client certificates against this CA bundle
+    #[argh(option)]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// certificate revocation list for client certificates
+    #[argh(option)]
+    pub tls_client_crl: Option<PathBuf>,
+
+    /// accept clients without a certificate
+    #[argh(switch)]
+    pub tls_client_optional: bool,
+
+    /// obtain a certificate for this DNS name via ACME, can be repeated
+    #[argh(option)]
+    pub acme: Vec<String>,
+
+    /// ACME directory URL (default: Let's Encrypt)
+    #[argh(option, default = "instant_acme::LetsEncrypt::Production.url().into()")]
+    pub acme_directory: String,
+
+    /// contact URI for the ACME account, such as mailto:admin@example.com
+    #[argh(option)]
+    pub acme_contact: Vec<String>,
+
+    /// directory for the ACME account and certificates, must be outside the served root
+    #[argh(option)]
+    pub acme_cache: Option<PathBuf>,
+
+    /// trust this extra root certificate for the ACME directory, such as Pebble's
+    #[argh(option)]
+    pub acme_ca: Option<PathBuf>,
+
+    /// agree to the ACME directory's terms of service, required by --acme
+    #[argh(switch)]
+    pub acme_agree_tos: bool,
+
+    /// ACME challenge: tls-alpn-01 or http-01, which needs --http-redirect (default: tls-alpn-01)
+    #[argh(option, default = "tls::AcmeChallenge::default()")]
+    pub acme_challenge: tls::AcmeChallenge,
+
+    /// also listen for plain HTTP here and redirect it to HTTPS, such as 0.0.0.0:80
+    #[argh(option)]
+    pub http_redirect: Option<SocketAddr>,
+
+    /// send Strict-Transport-Security with this max-age in seconds
+    #[argh(option)]
+    pub hsts: Option<u64>,
+
+    /// add includeSubDomains to Strict-Transport-Security
+    #[argh(switch)]
+    pub hsts_include_subdomains: bool,
+
+    /// add preload to Strict-Transport-Security
+    #[argh(switch)]
+    pub hsts_preload: bool,
+
+    /// seconds to wait for the PROXY header and TLS handshake, 0 waits forever (default: 10)
+    #[argh(option, default = "10")]
+    pub handshake_timeout: u64,
+
+    /// seconds to wait for an HTTP/1 request head, also between requests, 0 waits forever (default: 30)
+    #[argh(option, default = "30")]
+    pub header_timeout: u64,
+
+    /// seconds a connection may go without traffic and no response in flight, 0 keeps it forever, the header timeout ends a shorter HTTP/1 wait first (default: 60)
+    #[argh(option, default = "60")]
+    pub idle_timeout: u64,
+
+    /// seconds to wait on a client that takes none of the response, 0 waits forever (default: 60)
+    #[argh(option, default = "60")]
+    pub write_timeout: u64,
+
+    /// most connections served at once, more wait in the listen backlog, 0 for no limit (default: 0)
+    #[argh(option, default = "0")]
+    pub max_connections: usize,
+
+    /// most connections from one client address, more are closed, 0 for no limit (default: 0)
+    #[argh(option, default = "0")]
+    pub max_connections_per_ip: usize,
+
+    /// most bytes a second sent to all clients together, with an optional k, m or g suffix
+    #[argh(option)]
+    pub throttle: Option<Size>,
+
+    /// most bytes a second sent over one connection
+    #[argh(option)]
+    pub throttle_conn: Option<Size>,
+
+    /// most bytes a second for each connection below a path, instead of --throttle-conn, 0 for no limit: <prefix>=<rate>
+    #[argh(option)]
+    pub throttle_path: Vec<PathRate>,
+
+    /// compress text files and directory listings for clients that accept gzip, br or zstd
+    #[argh(switch)]
+    pub compress: bool,
+
+    /// smallest file to compress (default: 1k)
+    #[argh(option, default = "Size(1 << 10)")]
+    pub compress_min: Size,
+
+    /// largest file to compress (default: 64m)
+    #[argh(option, default = "Size(64 << 20)")]
+    pub compress_max: Size,
+
+    /// keep compressed files in this directory, by ETag
+    #[argh(option)]
+    pub compress_cache: Option<PathBuf>,
+
+    /// most the compress cache keeps, removing the oldest files first (default: 1g)
+    #[argh(option, default = "Size(1 << 30)")]
+    pub compress_cache_max: Size,
+
+    /// copy file bodies through userspace even on plaintext connections, instead of sendfile
+    #[argh(switch)]
+    pub no_sendfile: bool,
+
+    /// after the TLS handshake, hand encryption to the kernel so HTTPS can use sendfile too, Linux only
+    #[argh(switch)]
+    pub ktls: bool,
+
+    /// raise the open file limit to the hard limit at startup
+    #[argh(switch)]
+    pub raise_nofile: bool,
+
+    /// on SIGTERM or SIGINT, wait this many seconds for running transfers to finish (default: 30)
+    #[argh(option, default = "30")]
+    pub drain_timeout: u64
+}
+
+struct Bind {
+    addr: Addr,
+
+    /// `None` serves TLS on TCP whenever a certificate is configured,
+    /// Unix sockets are plain since they sit behind a local proxy.
+    tls: Option<bool>,
+    proxy: ProxyProtocol,
+    mode: Option<u32>,
+    owner: Option<String>
+}
+
+impl FromStr for Bind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut iter = s.split(',');
+        let addr = iter.next().unwrap_or_default().parse()?;
+        let mut bind = Bind { addr, tls: None, proxy: ProxyProtocol::Off, mode: None, owner: None };
+
+        for opt in iter {
+            match opt.split_once('=') {
+                None if opt == "tls" => bind.tls = Some(true),
+                None if opt == "plain" => bind.tls = Some(false),
+                None if opt == "proxy" => bind.proxy = ProxyProtocol::Required,
+                Some(("proxy", mode)) => bind.proxy = mode.parse()?,
+                Some(("mode", mode)) => bind.mode = Some(u32::from_str_radix(mode, 8)
+                    .map_err(|err| format!("bad mode {}: {}", mode, err))?),
+                Some(("owner", owner)) => bind.owner = Some(owner.into()),
+                _ => return Err(format!("unknown bind option: {}", opt))
+            }
+        }
+
+        if !matches!(bind.addr, Addr::Unix(_)) && (bind.mode.is_some() || bind.owner.is_some()) {
+            return Err("mode and owner only apply to unix sockets".into());
+        }
+
+        Ok(bind)
+    }
+}
+
+/// An absolute URL path, kept without the trailing `/`.
+struct BasePath(String);
+
+impl FromStr for BasePath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.starts_with('/') || !s.bytes().all(|b| b.is_ascii_graphic()) || s.contains(['?', '#']) {
+            return Err(format!("bad base path: {}", s));
+        }
+
+        Ok(BasePath(s.trim_end_matches('/').into()))
+    }
+}
+
+/// A byte count with an optional k, m or g suffix.
+struct Size(u64);
+
+impl FromStr for Size {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (num, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+            Some((i, _)) => s.split_at(i),
+            None => (s, "")
+        };
+        let shift = match unit.to_ascii_lowercase().as_str() {
+            "" => 0,
+            "k" => 10,
+            "m" => 20,
+            "g" => 30,
+            _ => return Err(format!("bad size unit: {}", s))
+        };
+
+        num.parse::<u64>()
+            .ok()
+            .and_then(|num| num.checked_mul(1 << shift))
+            .map(Size)
+            .ok_or_else(|| format!("bad size: {}", s))
+    }
+}
+
+struct PathRate {
+    prefix: String,
+    rate: Size
+}
+
+impl FromStr for PathRate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, rate) = s.split_once('=')
+            .ok_or("expected <prefix>=<rate>")?;
+
+        Ok(PathRate { prefix: prefix.into(), rate: rate.parse()? })
+    }
+}
+
+struct AuthRule {
+    prefix: String,
+    realm: String,
+    htpasswd: PathBuf
+}
+
+impl FromStr for AuthRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, rest) = s.split_once('=')
+            .ok_or("expected <prefix>=<realm>:<htpasswd file>")?;
+        let (realm, htpasswd) = rest.split_once(':')
+            .ok_or("expected <prefix>=<realm>:<htpasswd file>")?;
+
+        Ok(AuthRule {
+            prefix: prefix.into(),
+            realm: realm.into(),
+            htpasswd: htpasswd.into()
+        })
+    }
+}
+
+struct VHost {
+    name: String,
+    root: PathBuf,
+    index: Option<bool>,
+    symlink: Option<SymlinkPolicy>,
+    show_hidden: bool,
+    auth: Vec<AuthRule>,
+    public: Vec<String>,
+    cert_allow: Vec<String>
+}
+
+impl FromStr for VHost {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, rest) = s.split_once('=')
+            .ok_or("expected <name>=<root>[,<option>...]")?;
+        let mut iter = rest.split(',');
+        let root = iter.next().unwrap_or_default();
+
+        let mut vhost = VHost {
+            name: name.to_ascii_lowercase(),
+            root: root.into(),
+            index: None,
+            symlink: None,
+            show_hidden: false,
+            auth: Vec::new(),
+            public: Vec::new(),
+            cert_allow: Vec::new()
+        };
+
+        for opt in iter {
+            match opt.split_once('=') {
+                None if opt == "index" => vhost.index = Some(true),
+                None if opt == "noindex" => vhost.index = Some(false),
+                None if opt == "show-hidden" => vhost.show_hidden = true,
+                Some(("symlink", policy)) => vhost.symlink = Some(policy.parse()?),
+                Some(("auth", rule)) => vhost.auth.push(rule.parse()?),
+                Some(("public", prefix)) => vhost.public.push(prefix.into()),
+                Some(("cert-allow", rule)) => vhost.cert_allow.push(rule.into()),
+                _ => return Err(format!("unknown vhost option: {}", opt))
+            }
+        }
+
+        Ok(vhost)
+    }
+}
+
+struct SniCert {
+    name: String,
+    cert: PathBuf,
+    key: Option<PathBuf>
+}
+
+impl FromStr for SniCert {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, rest) = s.split_once('=')
+            .ok_or("expected <name>=<cert>[,<key>]")?;
+        let (cert, key) = match rest.split_once(',') {
+            Some((cert, key)) => (cert, Some(key.into())),
+            None => (rest, None)
+        };
+
+        Ok(SniCert { name: name.into(), cert: cert.into(), key })
+    }
+}
+
+fn load_auth(rules: &[AuthRule], public: &[String], cert_allow: &[String]) -> anyhow::Result<Auth> {
+    let mut auth = Auth::default();
+
+    for rule in rules {
+        let users = Htpasswd::open(&rule.htpasswd)
+            .with_context(|| format!("load htpasswd: {}", rule.htpasswd.display()))?;
+        auth.protect(&rule.prefix, Arc::new(Realm { name: rule.realm.clone(), users }));
+    }
+
+    for prefix in public {
+        auth.public(prefix);
+    }
+
+    for rule in cert_allow {
+        let (prefix, name) = rule.split_once('=')
+            .context("expected <prefix>=<name>")?;
+        auth.allow_cert(prefix, name);
+    }
+
+    Ok(auth)
+}
+
+fn load_passphrase(options: &Options) -> anyhow::Result<Option<Arc<[u8]>>> {
+    if let Some(path) = options.tls_key_pass_file.as_ref() {
+        let mut pass = fs::read(path)
+            .with_context(|| format!("read passphrase: {}", path.display()))?;
+        while let Some(b'\n' | b'\r') = pass.last() {
+            pass.pop();
+        }
+        Ok(Some(pass.into()))
+    } else if let Some(name) = options.tls_key_pass_env.as_ref() {
+        let pass = env::var_os(name)
+            .with_context(|| format!("passphrase env not found: {}", name))?;
+        Ok(Some(pass.into_encoded_bytes().into()))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Set the owner, then the mode, of a Unix socket, `owner` is `<user>[:<group>]` by name or id.
+#[cfg(unix)]
+fn set_owner(path: &Path, owner: &str, mode: Option<u32>) -> anyhow::Result<()> {
+    use std::os::unix::fs::{ chown, PermissionsExt };
+
+    let (user, group) = match owner.split_once(':') {
+        Some((user, group)) => (user, Some(group)),
+        None => (owner, None)
+    };
+    let uid = Some(user)
+        .filter(|user| !user.is_empty())
+        .map(|user| lookup_id(user, true))
+        .transpose()?;
+    let gid = group
+        .filter(|group| !group.is_empty())
+        .map(|group| lookup_id(group, false))
+        .transpose()?;
+    chown(path, uid, gid)
+        .with_context(|| format!("chown {}: {}", path.display(), owner))?;
+
+    // only opened up once it belongs to the right group
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn lookup_id(name: &str, is_user: bool) -> anyhow::Result<u32> {
+    use std::ffi::CString;
+
+    if let Ok(id) = name.parse() {
+        return Ok(id);
+    }
+
+    let cname = CString::new(name)?;
+
+    // only called during startup, before any other thread touches the passwd database
+    let id = unsafe {
+        if is_user {
+            let pw = libc::getpwnam(cname.as_ptr());
+            (!pw.is_null()).then(|| (*pw).pw_uid)
+        } else {
+            let gr = libc::getgrnam(cname.as_ptr());
+            (!gr.is_null()).then(|| (*gr).gr_gid)
+        }
+    };
+
+    id.with_context(|| format!("unknown {}: {}", if is_user { "user" } else { "group" }, name))
 }
 
-fn load_cert_and_key(path: &Path)
-    -> anyhow::Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>
-{
-    let mut reader = Cursor::new(fs::read(path)?);
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
 
-    let cert = rustls_pemfile::certs(&mut reader)
-        .collect::<Result<Vec<_>, _>>()
-        .context("Bad certs")?;
+/// Raise the soft open file limit to the hard one, so more connections fit.
+#[cfg(unix)]
+fn raise_nofile() -> io::Result<()> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let from = limit.rlim_cur;
+
+    // macOS refuses anything above OPEN_MAX, even when the hard limit is unlimited
+    #[cfg(target_os = "macos")]
+    let to = cmp::min(limit.rlim_max, libc::OPEN_MAX as libc::rlim_t);
+    #[cfg(not(target_os = "macos"))]
+    let to = limit.rlim_max;
+
+    if from < to {
+        limit.rlim_cur = to;
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    info!(from, to = limit.rlim_cur, "rlimit/nofile");
+    Ok(())
+}
+
+/// Accept the next connection, backing off while accepting fails, such as on EMFILE.
+///
+/// Returns `None` once `shutdown` fires.
+async fn accept(listener: &Listener, shutdown: &mut watch::Receiver<bool>) -> Option<(Socket, Option<SocketAddr>)> {
+    let mut backoff = ACCEPT_BACKOFF_MIN;
+
+    loop {
+        let result = tokio::select!{
+            result = listener.accept() => result,
+            _ = shutdown.changed() => return None
+        };
+
+        match result {
+            Ok(conn) => return Some(conn),
+
+            // only this connection is gone, no reason to wait
+            Err(err) if matches!(err.kind(), io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset) =>
+                debug!(?err, "accept"),
+            Err(err) => {
+                error!(?err, ?backoff, "accept");
+
+                tokio::select!{
+                    _ = time::sleep(backoff) => (),
+                    _ = shutdown.changed() => return None
+                }
+                backoff = cmp::min(backoff * 2, ACCEPT_BACKOFF_MAX);
+            }
+        }
+    }
+}
+
+/// Accept until `shutdown` fires, then wait for the open connections to finish.
+async fn serve(
+    listener: Listener,
+    acceptor: Option<TlsAcceptor>,
+    proxy: ProxyProtocol,
+    webdir: WebDir,
+    http_builder: Arc<HttpBuilder<hyper_util::rt::tokio::TokioExecutor>>,
+    mut shutdown: watch::Receiver<bool>
+) {
+    let graceful = GracefulShutdown::new();
+
+    loop {
+        let permit = tokio::select!{
+            permit = webdir.limits.acquire() => permit,
+            _ = shutdown.changed() => break
+        };
+        let (socket, via) = match accept(&listener, &mut shutdown).await {
+            Some(conn) => conn,
+            None => break
+        };
+        let mut webdir = webdir.clone();
+        let acceptor = acceptor.clone();
+        let http_builder = http_builder.clone();
+        let watcher = graceful.watcher();
+
+        let timeouts = webdir.timeouts.clone();
+        let limits = webdir.limits.clone();
+        let ktls = webdir.ktls;
+
+        // anyone could claim any address otherwise
+        let proxy = match proxy {
+            ProxyProtocol::Optional if !webdir.trusted_proxies.contains(via.map(|addr| addr.ip())) =>
+                ProxyProtocol::Off,
+            proxy => proxy
+        };
+
+        let fut = async move {
+            let _permit = permit;
+            let in_flight = Arc::new(InFlight::default());
+            let socket = TimeoutStream::new(socket, timeouts.clone())
+                .in_flight(in_flight.clone());
+
+            let handshake = async {
+                let (socket, proxy) = ProxyStream::accept(socket, proxy).await
+                    .map_err(|err| anyhow::format_err!("proxy header from {:?}: {}", via, err))?;
+                let addr = match proxy {
+                    Proxy::Client(client) => Some(client),
+                    Proxy::None | Proxy::Local => via
+                };
+
+                // refused before we spend a TLS handshake on it
+                let ip_permit = match addr {
+                    Some(addr) => match limits.acquire_ip(addr.ip()) {
+                        Some(permit) => Some(permit),
+                        None => {
+                            info!(peer = ?addr, "limit/per ip");
+                            return Ok(None);
+                        }
+                    },
+                    None => None
+                };
+
+                let stream = WebStream::new(socket, acceptor, ktls).await?;
+                #[cfg(target_os = "linux")]
+                let stream = if ktls { stream.into_ktls()? } else { stream };
+                Ok(Some((stream, proxy, addr, ip_permit))) as anyhow::Result<_>
+            };
+            let handshake = match timeouts.handshake {
+                Some(timeout) => match time::timeout(timeout, handshake).await {
+                    Ok(result) => result?,
+                    Err(_) => {
+                        timeouts.hit(Timeout::Handshake);
+                        return Ok(());
+                    }
+                },
+                None => handshake.await?
+            };
+            let (stream, proxy, addr, _ip_permit): (WebStream<_>, _, _, _) = match handshake {
+                Some(handshake) => handshake,
+                None => return Ok(())
+            };
+
+            match proxy {
+                Proxy::None => info!(peer = ?addr, "peer"),
+                _ => info!(peer = ?addr, ?via, "peer")
+            }
+
+            if stream.is_acme_challenge() {
+                info!(peer = ?addr, "acme/tls-alpn-01 validation");
+                return Ok(());
+            }
+            webdir.peer = Peer {
+                addr,
+                cert: stream.client_cert().map(Arc::new),
+                server_name: stream.server_name().map(|name| name.to_ascii_lowercase().into()),
+                secure: stream.is_tls(),
+                prefix: None,
+                bucket: webdir.throttle.connection(),
+                path_buckets: Default::default(),
+                sendfile: None,
+                in_flight: Some(in_flight)
+            };
+            if webdir.zero_copy && !matches!(stream, WebStream::Tls(_)) {
+                webdir.peer.sendfile = Some(Arc::new(Sendfile::default()));
+            }
+            let stream = SendfileStream::new(stream, webdir.peer.sendfile.clone());
+            let stream = hyper_util::rt::tokio::TokioIo::new(stream);
+
+            let conn = http_builder.serve_connection(stream, webdir);
+            match watcher.watch(conn).await {
+                Err(err) if !timeouts.check(&*err) => anyhow::bail!("http serve: {:?}", err),
+                _ => Ok(())
+            }
+        }.unwrap_or_else(|err| error!(?err, "socket/err"));
+
+        tokio::spawn(fut.in_current_span());
+    }
+
+    drop(listener);
+    info!(connections = graceful.count(), "shutdown/draining");
+    graceful.shutdown().await;
+}
+
+/// How an inherited socket is served, by its name: `http` or `plain`, `https` or `tls`,
+/// then `+proxy` or `+proxy=<mode>` if a PROXY header comes first.
+fn inherited_mode(name: &str) -> anyhow::Result<(Option<bool>, ProxyProtocol)> {
+    let (kind, proxy) = match name.split_once('+') {
+        Some((kind, "proxy")) => (kind, ProxyProtocol::Required),
+        Some((kind, proxy)) => match proxy.strip_prefix("proxy=") {
+            Some(mode) => (kind, mode.parse().map_err(anyhow::Error::msg)?),
+            None => anyhow::bail!("unknown socket option: {}", proxy)
+        },
+        None => (name, ProxyProtocol::Off)
+    };
+    let tls = match kind {
+        "http" | "plain" => Some(false),
+        "https" | "tls" => Some(true),
+        _ => None
+    };
+
+    Ok((tls, proxy))
+}
+
+/// The name a new process finds a socket by, so it serves it the same without a --bind for it.
+#[cfg(unix)]
+fn handoff_name(tls: bool, proxy: ProxyProtocol) -> String {
+    let kind = if tls { "https" } else { "http" };
+    match proxy {
+        ProxyProtocol::Off => kind.into(),
+        ProxyProtocol::Required => format!("{}+proxy", kind),
+        proxy => format!("{}+proxy={}", kind, proxy.name())
+    }
+}
+
+/// What a self-signed certificate for a wildcard bind covers: localhost, the host name,
+/// and the addresses of every interface, of both families for `[::]`.
+#[cfg(unix)]
+fn local_names(v6: bool) -> io::Result<Vec<String>> {
+    use std::ffi::CStr;
+    use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+
+    let mut names = vec!["localhost".to_owned()];
+
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } == 0 {
+        if let Some(host) = CStr::from_bytes_until_nul(&buf).ok().and_then(|host| host.to_str().ok()) {
+            if !host.is_empty() && host.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.') {
+                names.push(host.to_ascii_lowercase());
+            }
+        }
+    }
+
+    let mut ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut next = ifaddrs;
+    while let Some(ifa) = unsafe { next.as_ref() } {
+        next = ifa.ifa_next;
+
+        let addr = match unsafe { ifa.ifa_addr.as_ref() } {
+            Some(addr) => addr,
+            None => continue
+        };
+        let ip = match libc::c_int::from(addr.sa_family) {
+            libc::AF_INET => {
+                let sin = unsafe { &*(addr as *const libc::sockaddr).cast::<libc::sockaddr_in>() };
+                IpAddr::from(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)))
+            },
+            libc::AF_INET6 if v6 => {
+                let sin6 = unsafe { &*(addr as *const libc::sockaddr).cast::<libc::sockaddr_in6>() };
+                let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+
+                // useless in a URL without the zone
+                if ip.is_unicast_link_local() {
+                    continue;
+                }
+                IpAddr::from(ip)
+            },
+            _ => continue
+        };
+        names.push(ip.to_string());
+    }
+
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    Ok(names)
+}
+
+#[cfg(not(unix))]
+fn local_names(_: bool) -> io::Result<Vec<String>> {
+    Ok(vec!["localhost".to_owned()])
+}
+
+/// Take the inherited socket that already listens on `addr`.
+fn take_inherited(inherited: &mut Vec<(String, Listener)>, addr: &Addr) -> Option<Listener> {
+    let pos = inherited.iter()
+        .position(|(_, listener)| listener.local_addr().ok().as_ref() == Some(addr))?;
+    let (name, listener) = inherited.remove(pos);
+    info!(%addr, %name, "bind/inherited");
+    Some(listener)
+}
+
+async fn serve_redirect(
+    listener: Listener,
+    http_builder: Arc<HttpBuilder<hyper_util::rt::tokio::TokioExecutor>>,
+    redirect: Redirect,
+    timeouts: Arc<Timeouts>,
+    mut shutdown: watch::Receiver<bool>
+) {
+    let graceful = GracefulShutdown::new();
+
+    loop {
+        let (socket, addr) = match accept(&listener, &mut shutdown).await {
+            Some(conn) => conn,
+            None => break
+        };
+        let redirect = redirect.clone();
+        let http_builder = http_builder.clone();
+        let watcher = graceful.watcher();
+        let timeouts = timeouts.clone();
+
+        let fut = async move {
+            debug!(?addr, "redirect/peer");
+
+            let socket = TimeoutStream::new(socket, timeouts.clone());
+            let conn = http_builder.serve_connection(hyper_util::rt::tokio::TokioIo::new(socket), redirect);
+            match watcher.watch(conn).await {
+                Err(err) if !timeouts.check(&*err) => anyhow::bail!("http serve: {:?}", err),
+                _ => Ok(())
+            }
+        }.unwrap_or_else(|err| error!(?err, "redirect/err"));
+
+        tokio::spawn(fut);
+    }
+
+    drop(listener);
+    graceful.shutdown().await;
+}
+
+#[cfg(unix)]
+async fn watchdog(interval: Duration) {
+    let mut interval = time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(err) = systemd::notify("WATCHDOG=1") {
+            error!(?err, "systemd/watchdog");
+        }
+    }
+}
+
+/// Wait for SIGUSR2, then start a new process on our sockets.
+///
+/// Returns once the new process has taken over, a failed upgrade keeps us serving.
+#[cfg(unix)]
+async fn upgrade_signal(exe: &Path, handoff: &[(RawFd, String)]) {
+    let mut usr2 = match signal(SignalKind::user_defined2()) {
+        Ok(usr2) => usr2,
+        Err(err) => {
+            error!(?err, "signal/upgrade");
+            return future::pending().await
+        }
+    };
+
+    loop {
+        usr2.recv().await;
+        info!(?exe, "upgrade/start");
+
+        match upgrade::spawn(exe, handoff).await {
+            Ok(pid) => {
+                info!(pid, "upgrade/ready");
+                return
+            },
+            Err(err) => error!(?err, "upgrade")
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let term = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => { term.recv().await; },
+            Err(err) => {
+                error!(?err, "signal/terminate");
+                future::pending::<()>().await
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let term = future::pending::<()>();
+
+    tokio::select!{
+        _ = term => (),
+        _ = tokio::signal::ctrl_c() => ()
+    }
+}
+
+async fn watch_certs(resolver: Arc<tls::Resolver>, interval: u64) {
+    #[cfg(unix)]
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            error!(?err, "tls/reload");
+            return
+        }
+    };
+    let mut interval = (interval > 0)
+        .then(|| time::interval(Duration::from_secs(interval)));
+
+    loop {
+        #[cfg(unix)]
+        let hup = hangup.recv();
+        #[cfg(not(unix))]
+        let hup = future::pending::<Option<()>>();
 
-    reader.set_position(0);
-    let key = rustls_pemfile::private_key(&mut reader)
-        .context("Bad private key")?
-        .context("not found private key")?;
+        let tick = async {
+            match interval.as_mut() {
+                Some(interval) => { interval.tick().await; },
+                None => future::pending().await
+            }
+        };
 
-    Ok((cert, key))
+        let force = tokio::select!{
+            _ = hup => true,
+            _ = tick => false
+        };
+
+        resolver.reload(force);
+    }
 }
 
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
+/// What the environment handed us, taken before any other thread could read it.
+#[cfg(unix)]
+type Inherited = (systemd::ListenEnv, upgrade::UpgradeEnv);
+#[cfg(not(unix))]
+type Inherited = ();
+
+fn main() -> anyhow::Result<()> {
+    // changing the environment races with other threads, so before the runtime starts them
+    #[cfg(unix)]
+    let env = (systemd::ListenEnv::take(), upgrade::UpgradeEnv::take());
+    #[cfg(not(unix))]
+    let env = ();
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(env))
+}
+
+async fn run(env: Inherited) -> anyhow::Result<()> {
     let options: Options = argh::from_env();
+    #[cfg(unix)]
+    let exe = env::current_exe()?;
 
     tracing_subscriber::fmt()
         .compact()
         .init();
 
+    #[cfg(unix)]
+    if options.raise_nofile {
+        raise_nofile().context("raise open file limit")?;
+    }
+
     let root =
         if let Some(ref p) = options.root { Arc::from(&*p.canonicalize()?) }
         else { Arc::from(&*env::current_dir()?) };
 
-    let acceptor = if let Some(cert) = options.https.as_ref() {
-        let (certs, key) = load_cert_and_key(cert)?;
-        let mut config = ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(certs, key)?;
+    let cert_and_key = match (options.https.as_ref(), options.tls_cert.as_ref(), options.tls_key.as_ref()) {
+        (Some(pem), None, None) => Some((pem, pem)),
+        (None, Some(cert), key) => Some((cert, key.unwrap_or(cert))),
+        (None, None, None) => None,
+        (None, None, Some(_)) => anyhow::bail!("--tls-key requires --tls-cert"),
+        (Some(_), ..) => anyhow::bail!("--https conflicts with --tls-cert/--tls-key")
+    };
+    anyhow::ensure!(
+        !(options.self_signed && cert_and_key.is_some()),
+        "--self-signed conflicts with --https/--tls-cert"
+    );
+    anyhow::ensure!(!options.ktls || cfg!(target_os = "linux"), "--ktls is only supported on Linux");
+
+    let mut resolver = tls::Resolver::default();
+    let passphrase = load_passphrase(&options)?;
+
+    if let Some((cert, key)) = cert_and_key {
+        resolver.set_default(tls::Source {
+            cert: cert.clone(),
+            key: key.clone(),
+            passphrase: passphrase.clone()
+        })?;
+    }
+
+    if options.self_signed {
+        let mut names = Vec::new();
+        for bind in &options.bind {
+            match bind.addr {
+                // a client may come in over any of them
+                Addr::Tcp(addr) if addr.ip().is_unspecified() =>
+                    names.extend(local_names(addr.is_ipv6()).context("self-signed names")?),
+                Addr::Tcp(addr) => names.push(addr.ip().to_string()),
+                Addr::Unix(_) => ()
+            }
+        }
+        names.sort();
+        names.dedup();
+        names.extend(options.tls_san.iter().cloned());
+        if names.is_empty() {
+            names.push("localhost".into());
+        }
+
+        let certified = tls::self_signed(&names, options.self_signed_dir.as_deref())?;
+        info!(
+            ?names,
+            fingerprint = %tls::fingerprint(certified.end_entity_cert()?),
+            "self-signed certificate"
+        );
+        resolver.set_default_key(certified);
+    }
+
+    for sni in &options.tls_sni {
+        resolver.insert(&sni.name, tls::Source {
+            cert: sni.cert.clone(),
+            key: sni.key.clone().unwrap_or_else(|| sni.cert.clone()),
+            passphrase: passphrase.clone()
+        }).with_context(|| format!("load certificate for {}", sni.name))?;
+    }
+
+    #[cfg(unix)]
+    let (mut inherited, ready) = {
+        let (listen, mut upgrade) = env;
+        let mut inherited = listen.listen_fds().context("socket activation")?;
+        inherited.extend(upgrade.inherited().context("upgrade")?);
+        (inherited, upgrade.ready().context("upgrade")?)
+    };
+    #[cfg(not(unix))]
+    let mut inherited: Vec<(String, Listener)> = {
+        let () = env;
+        Vec::new()
+    };
+
+    anyhow::ensure!(
+        !options.bind.is_empty() || !inherited.is_empty(),
+        "at least one --bind is required"
+    );
+
+    let challenges = Arc::new(tls::Challenges::default());
+
+    if !options.acme.is_empty() {
+        let cache = options.acme_cache.as_ref()
+            .context("--acme requires --acme-cache")?;
+        fs::create_dir_all(cache)?;
+        anyhow::ensure!(
+            !cache.canonicalize()?.starts_with(&root),
+            "--acme-cache must not be under the served root"
+        );
+        anyhow::ensure!(
+            !options.acme.iter().any(|name| name.starts_with("*.")),
+            "wildcard names can not be validated by --acme"
+        );
+        anyhow::ensure!(
+            options.acme_agree_tos,
+            "--acme requires --acme-agree-tos, read the terms of {}", options.acme_directory
+        );
+        anyhow::ensure!(
+            options.acme_challenge != tls::AcmeChallenge::Http01 || options.http_redirect.is_some(),
+            "--acme-challenge http-01 requires --http-redirect on port 80"
+        );
+
+        let acme = tls::Acme {
+            names: options.acme.iter().map(|name| name.to_ascii_lowercase()).collect(),
+            directory: options.acme_directory.clone(),
+            contact: options.acme_contact.clone(),
+            cache: cache.clone(),
+            root: options.acme_ca.clone(),
+            challenge: options.acme_challenge,
+            challenges: challenges.clone(),
+            agree_tos: options.acme_agree_tos
+        };
+        tokio::spawn(acme.start(&mut resolver)?);
+    }
+
+    let acceptor = if !resolver.is_empty() {
+        let resolver = Arc::new(resolver);
+        tokio::spawn(watch_certs(resolver.clone(), options.tls_reload_interval));
+        let builder = ServerConfig::builder();
+        let builder = if let Some(ca) = options.tls_client_ca.as_ref() {
+            let verifier = tls::client_verifier(
+                ca,
+                options.tls_client_crl.as_deref(),
+                !options.tls_client_optional
+            )?;
+            builder.with_client_cert_verifier(verifier)
+        } else {
+            builder.with_no_client_auth()
+        };
+        let mut config = builder.with_cert_resolver(resolver);
+        config.enable_secret_extraction = options.ktls;
         config.alpn_protocols = vec!["h2".into(), "http/1.1".into()];
+        if !options.acme.is_empty() {
+            config.alpn_protocols.push(tls::ACME_TLS_ALPN.into());
+        }
         let config = Arc::new(config);
         Some(TlsAcceptor::from(config))
     } else {
         None
     };
 
-    let webdir = WebDir::new(root, options.index)?;
-    let listener = TcpListener::bind(&options.bind).await?;
+    let mut webdir = WebDir::new(root, options.index)?;
+    webdir.symlink = options.symlink;
+    webdir.filter = Arc::new(Filter::new(&webdir.root, !options.show_hidden, &options.ignore)?);
+    webdir.auth = Arc::new(load_auth(&options.auth, &options.public, &options.cert_allow)?);
+    webdir.precompressed = options.precompressed;
+    webdir.zero_copy &= !options.no_sendfile;
+    webdir.ktls = options.ktls;
+    if options.compress {
+        let mut compression = Compression::default();
+        compression.min_size = options.compress_min.0;
+        compression.max_size = options.compress_max.0;
+        compression.cache = options.compress_cache.clone();
+        compression.cache_max = options.compress_cache_max.0;
+        if let Some(dir) = options.compress_cache.as_ref() {
+            fs::create_dir_all(dir)
+                .and_then(|_| compression.clean())
+                .with_context(|| format!("compress cache: {}", dir.display()))?;
+        }
+        webdir.compression = Some(Arc::new(compression));
+    }
+    let secs = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+    let mut timeouts = Timeouts::default();
+    timeouts.handshake = secs(options.handshake_timeout);
+    timeouts.header = secs(options.header_timeout);
+    timeouts.idle = secs(options.idle_timeout);
+    timeouts.write = secs(options.write_timeout);
+    webdir.timeouts = Arc::new(timeouts);
+    let mut throttle = Throttle::default();
+    throttle.global = options.throttle.as_ref()
+        .filter(|rate| rate.0 > 0)
+        .map(|rate| Arc::new(Bucket::new(rate.0)));
+    throttle.per_conn = options.throttle_conn.as_ref()
+        .map(|rate| rate.0)
+        .filter(|&rate| rate > 0);
+    for rule in &options.throttle_path {
+        throttle.set_path(&rule.prefix, rule.rate.0);
+    }
+    webdir.throttle = Arc::new(throttle);
+
+    let limit = |max: usize| (max > 0).then_some(max);
+    webdir.limits = Arc::new(Limits::new(limit(options.max_connections), limit(options.max_connections_per_ip)));
+    webdir.trusted_proxies = Arc::new(TrustedProxies(options.trusted_proxy.clone()));
+    if let Some(base_path) = options.base_path.as_ref() {
+        webdir.base_path = Arc::from(base_path.0.as_str());
+    }
+
+    let mut hosts = HashMap::new();
+    for vhost in &options.vhost {
+        let mut host = WebDir::new(Arc::from(vhost.root.as_path()), vhost.index.unwrap_or(options.index))
+            .with_context(|| format!("vhost root: {}", vhost.root.display()))?
+            .inherit(&webdir);
+        host.symlink = vhost.symlink.unwrap_or(options.symlink);
+        host.filter = Arc::new(Filter::new(&host.root, !(vhost.show_hidden || options.show_hidden), &options.ignore)?);
+        // paths mean something else under another root
+        host.auth = Arc::new(load_auth(&vhost.auth, &vhost.public, &vhost.cert_allow)
+            .with_context(|| format!("vhost auth: {}", vhost.name))?);
+        hosts.insert(vhost.name.clone(), host);
+    }
+    webdir.hosts = Arc::new(hosts);
+    webdir.strict_hosts = options.strict_hosts;
+    if !options.acme.is_empty() {
+        webdir.acme = Some(challenges.clone());
+    }
+
+    if let Some(max_age) = options.hsts {
+        anyhow::ensure!(acceptor.is_some(), "--hsts requires HTTPS");
+        anyhow::ensure!(
+            !options.hsts_preload || options.hsts_include_subdomains,
+            "--hsts-preload requires --hsts-include-subdomains"
+        );
+        webdir.hsts = Some(webdir::hsts(max_age, options.hsts_include_subdomains, options.hsts_preload));
+    }
+
     let mut http_builder = HttpBuilder::new(hyper_util::rt::tokio::TokioExecutor::new());
     http_builder
         .http1()
         .half_close(true)
         .max_buf_size(2048 * 1024)
         .timer(hyper_util::rt::tokio::TokioTimer::new())
+        .header_read_timeout(webdir.timeouts.header)
         .http2()
         .adaptive_window(true)
         .max_send_buf_size(2048 * 1024)
         .timer(hyper_util::rt::tokio::TokioTimer::new());
     let http_builder = Arc::new(http_builder);
 
-    info!("bind: {:?}", listener.local_addr());
+    let tls_for = |addr: &Addr, tls: Option<bool>| match tls {
+        Some(true) => acceptor.clone()
+            .with_context(|| format!("{}: tls requires a certificate", addr))
+            .map(Some),
+        Some(false) => Ok(None),
+        None => Ok(match addr {
+            Addr::Tcp(_) => acceptor.clone(),
+            Addr::Unix(_) => None
+        })
+    };
 
-    loop {
-        let result = listener.accept().await;
-        let webdir = webdir.clone();
-        let acceptor = acceptor.clone();
-        let http_builder = http_builder.clone();
+    let mut listeners = Vec::new();
+    #[cfg(unix)]
+    let mut handoff = Vec::new();
+    for bind in &options.bind {
+        let acceptor = tls_for(&bind.addr, bind.tls)?;
+        let listener = match (take_inherited(&mut inherited, &bind.addr), &bind.addr) {
+            (Some(listener), _) => listener,
+            #[cfg(unix)]
+            (None, Addr::Unix(path)) => {
+                // nobody else may connect before the owner is set
+                let mode = if bind.owner.is_some() { Some(0o600) } else { bind.mode };
+                let listener = Listener::bind_unix(path, mode)
+                    .with_context(|| format!("bind: {}", bind.addr))?;
+                if let Some(owner) = bind.owner.as_deref() {
+                    set_owner(path, owner, bind.mode)?;
+                }
 
-        let fut = async move {
-            let (socket, addr) = result?;
+                listener
+            },
+            (None, addr) => Listener::bind(addr).await
+                .with_context(|| format!("bind: {}", addr))?
+        };
 
-            info!(?addr, "peer");
+        #[cfg(unix)]
+        handoff.push((listener.as_raw_fd(), handoff_name(acceptor.is_some(), bind.proxy)));
+        listeners.push((listener, acceptor, bind.proxy));
+    }
 
-            let stream = WebStream::new(socket, acceptor).await?;
-            let stream = hyper_util::rt::tokio::TokioIo::new(stream);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut tasks = Vec::new();
 
-            http_builder
-                .serve_connection(stream, webdir)
-                .await
-                .map_err(|err| anyhow::format_err!("http serve: {:?}", err))?;
+    if let Some(addr) = options.http_redirect {
+        let port = listeners.iter()
+            .find_map(|(listener, acceptor, _)| match listener.local_addr() {
+                Ok(Addr::Tcp(addr)) if acceptor.is_some() => Some(addr.port()),
+                _ => None
+            })
+            .context("--http-redirect requires an HTTPS listener")?;
+        let addr = Addr::Tcp(addr);
+        let redirect_listener = match take_inherited(&mut inherited, &addr) {
+            Some(listener) => listener,
+            None => Listener::bind(&addr).await
+                .with_context(|| format!("bind: {}", addr))?
+        };
+        let redirect = Redirect {
+            port,
+            acme: (!options.acme.is_empty()).then(|| challenges.clone())
+        };
 
-            Ok(()) as anyhow::Result<()>
-        }.unwrap_or_else(|err| error!(?err, "socket/err"));
+        info!("redirect bind: {:?}", redirect_listener.local_addr());
+        #[cfg(unix)]
+        handoff.push((redirect_listener.as_raw_fd(), "redirect".to_owned()));
+        tasks.push(tokio::spawn(serve_redirect(
+            redirect_listener,
+            http_builder.clone(),
+            redirect,
+            webdir.timeouts.clone(),
+            shutdown_rx.clone()
+        )));
+    }
 
-        tokio::spawn(fut);
+    // sockets that no --bind asked for, their name picks plain or TLS and the PROXY mode
+    for (name, listener) in inherited {
+        let addr = listener.local_addr()?;
+        let (tls, proxy) = inherited_mode(&name)
+            .with_context(|| format!("inherited socket {}", addr))?;
+        let acceptor = tls_for(&addr, tls)?;
+        info!(%addr, %name, "bind/inherited");
+        #[cfg(unix)]
+        handoff.push((listener.as_raw_fd(), handoff_name(acceptor.is_some(), proxy)));
+        listeners.push((listener, acceptor, proxy));
+    }
+
+    anyhow::ensure!(
+        !webdir.trusted_proxies.is_empty()
+            || listeners.iter().all(|(_, _, proxy)| *proxy != ProxyProtocol::Optional),
+        "proxy=optional requires --trusted-proxy"
+    );
+
+    for (listener, acceptor, proxy) in listeners {
+        let addr = listener.local_addr()?;
+        info!(%addr, tls = acceptor.is_some(), ?proxy, "bind");
+
+        let span = info_span!("listener", %addr);
+        let fut = serve(listener, acceptor, proxy, webdir.clone(), http_builder.clone(), shutdown_rx.clone());
+        tasks.push(tokio::spawn(fut.instrument(span)));
     }
+
+    #[cfg(unix)] {
+        // the old process is still the main one until we take over
+        let state = match ready {
+            Some(ready) => {
+                ready.done().context("upgrade/ready")?;
+                info!("upgrade/took over");
+                format!("MAINPID={}\nREADY=1", std::process::id())
+            },
+            None => "READY=1".into()
+        };
+        if let Err(err) = systemd::notify(&state) {
+            error!(?err, "systemd/notify");
+        }
+        if let Some(interval) = systemd::watchdog_interval() {
+            tokio::spawn(watchdog(interval));
+        }
+    }
+
+    #[cfg(unix)]
+    let upgrade = upgrade_signal(&exe, &handoff);
+    #[cfg(not(unix))]
+    let upgrade = future::pending::<()>();
+
+    let upgraded = tokio::select!{
+        _ = shutdown_signal() => false,
+        _ = upgrade => true
+    };
+    info!(
+        transfers = webdir.transfers.len(),
+        upgraded,
+        handshake_timeouts = webdir.timeouts.count(Timeout::Handshake),
+        header_timeouts = webdir.timeouts.count(Timeout::Header),
+        idle_timeouts = webdir.timeouts.count(Timeout::Idle),
+        write_timeouts = webdir.timeouts.count(Timeout::Write),
+        "shutdown"
+    );
+
+    #[cfg(unix)]
+    if !upgraded {
+        if let Err(err) = systemd::notify("STOPPING=1") {
+            error!(?err, "systemd/notify");
+        }
+    }
+
+    let _ = shutdown_tx.send(true);
+    let drain = async {
+        future::join_all(tasks).await;
+        webdir.transfers.drained().await;
+    };
+
+    if time::timeout(Duration::from_secs(options.drain_timeout), drain).await.is_ok() {
+        info!("shutdown/drained");
+    } else {
+        let aborted = webdir.transfers.active();
+        warn!(count = aborted.len(), "shutdown/drain timeout");
+
+        for transfer in &aborted {
+            warn!(path = ?transfer.path, sent = transfer.sent, "shutdown/aborted transfer");
+        }
+    }
+
+    Ok(())
 }
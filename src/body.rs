@@ -1,22 +1,94 @@
+use std::io;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{ Context, Poll };
+use tokio::time;
 use tokio::sync::mpsc;
 use bytes::Bytes;
 use hyper::body::{ Body, SizeHint, Frame };
+use crate::transfer::Transfer;
+use crate::timeout::{ timed_out, Timeout, Timeouts, Responding };
+use crate::throttle::Bucket;
+use crate::encoding::Encoder;
+use crate::sendfile::{ Sendfile, FileBody };
 
 
-pub struct Sender(mpsc::Sender<Bytes>);
+pub struct Sender {
+    tx: mpsc::Sender<Bytes>,
+    timeouts: Option<Arc<Timeouts>>,
+    buckets: Vec<Arc<Bucket>>,
+    encoder: Option<Encoder>
+}
 
 pub struct ResponseBody {
     size: Option<u64>,
-    recv: mpsc::Receiver<Bytes>
+    recv: mpsc::Receiver<Bytes>,
+    file: Option<FileBody>,
+    transfer: Option<Transfer>,
+    responding: Option<Responding>
 }
 
 impl Sender {
-    pub async fn send_data(&mut self, data: Bytes) -> Result<(), mpsc::error::SendError<()>> {
-        self.0.send(data)
-            .await
-            .map_err(|_| mpsc::error::SendError(()))
+    /// Give up when the client takes nothing for the write timeout.
+    ///
+    /// Not counted, a stalled connection is counted by its stream,
+    /// this only catches a single HTTP/2 stream the client stopped reading.
+    pub fn timeout(mut self, timeouts: Arc<Timeouts>) -> Sender {
+        self.timeouts = Some(timeouts);
+        self
+    }
+
+    /// Pace the data by all of `buckets`.
+    pub fn throttle(mut self, buckets: Vec<Arc<Bucket>>) -> Sender {
+        self.buckets = buckets;
+        self
+    }
+
+    /// Compress the data with `encoder`, which needs a [`Sender::finish`].
+    pub fn compress(mut self, encoder: Option<Encoder>) -> Sender {
+        self.encoder = encoder;
+        self
+    }
+
+    pub async fn send_data(&mut self, data: Bytes) -> io::Result<()> {
+        let data = match self.encoder.as_mut() {
+            Some(encoder) => encoder.write(&data).await?,
+            None => data
+        };
+
+        self.send(data).await
+    }
+
+    /// Send what the encoder still holds.
+    pub async fn finish(mut self) -> io::Result<()> {
+        match self.encoder.take() {
+            Some(encoder) => {
+                let data = encoder.finish().await?;
+                self.send(data).await
+            },
+            None => Ok(())
+        }
+    }
+
+    async fn send(&mut self, data: Bytes) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        for bucket in &self.buckets {
+            bucket.take(data.len()).await;
+        }
+
+        let send = self.tx.send(data);
+        let result = match self.timeouts.as_deref() {
+            Some(Timeouts { write: Some(write), .. }) => match time::timeout(*write, send).await {
+                Ok(result) => result,
+                Err(_) => return Err(timed_out(Timeout::Write))
+            },
+            _ => send.await
+        };
+
+        result.map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "body closed"))
     }
 }
 
@@ -25,7 +97,10 @@ impl ResponseBody {
         let (_tx, rx) = mpsc::channel(1);
         ResponseBody {
             size: Some(0),
-            recv: rx
+            recv: rx,
+            file: None,
+            transfer: None,
+            responding: None
         }
     }
 
@@ -37,13 +112,40 @@ impl ResponseBody {
         });
         ResponseBody {
             size: Some(size),
-            recv: rx
+            recv: rx,
+            file: None,
+            transfer: None,
+            responding: None
         }
     }
 
     pub fn channel(size: Option<u64>) -> (Sender, ResponseBody) {
         let (tx, rx) = mpsc::channel(32);
-        (Sender(tx), ResponseBody { size, recv: rx })
+        (Sender { tx, timeouts: None, buckets: Vec::new(), encoder: None }, ResponseBody { size, recv: rx, file: None, transfer: None, responding: None })
+    }
+
+    /// `len` bytes of `file` from `offset`, for the connection behind `slot` to send.
+    pub fn sendfile(slot: Arc<Sendfile>, file: std::fs::File, offset: u64, len: u64) -> ResponseBody {
+        let (_tx, rx) = mpsc::channel(1);
+        ResponseBody {
+            size: Some(len),
+            recv: rx,
+            file: Some(FileBody::new(slot, file, offset, len)),
+            transfer: None,
+            responding: None
+        }
+    }
+
+    /// Count the sent bytes against `transfer`, which ends with the body.
+    pub fn track(mut self, transfer: Transfer) -> ResponseBody {
+        self.transfer = Some(transfer);
+        self
+    }
+
+    /// Keep the connection from going idle until the body is done.
+    pub fn responding(mut self, responding: Option<Responding>) -> ResponseBody {
+        self.responding = responding;
+        self
     }
 }
 
@@ -56,15 +158,27 @@ impl Body for ResponseBody {
     {
         let this = self.get_mut();
 
-        match this.recv.poll_recv(cx) {
+        let next = match this.file.as_mut() {
+            Some(file) => Poll::Ready(file.next_chunk()),
+            None => this.recv.poll_recv(cx)
+        };
+
+        match next {
             Poll::Ready(Some(buf)) => {
                 if let Some(size) = this.size.as_mut() {
                     *size -= buf.len() as u64;
                 }
+                if let Some(transfer) = this.transfer.as_ref() {
+                    transfer.sent(buf.len());
+                }
 
                 Poll::Ready(Some(Ok(Frame::data(buf))))
             },
-            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Ready(None) => {
+                this.transfer = None;
+                this.responding = None;
+                Poll::Ready(None)
+            },
             Poll::Pending => Poll::Pending
         }
     }
@@ -0,0 +1,453 @@
+use std::{ fs, io, mem };
+use std::cmp::Ordering;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ self, AtomicU64 };
+use smallvec::SmallVec;
+use bytes::Bytes;
+use mime::Mime;
+use rand::{ Rng, thread_rng, distributions::Alphanumeric };
+use http::{ HeaderMap, HeaderValue };
+use http::header::ACCEPT_ENCODING;
+use tokio::io::AsyncWriteExt;
+use tokio::task::block_in_place;
+use async_compression::Level;
+use async_compression::tokio::write::{ BrotliEncoder, ZstdEncoder, GzipEncoder };
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Br,
+    Zstd,
+    Gzip
+}
+
+impl Encoding {
+    /// Our preference when the client likes them equally.
+    pub const ALL: [Encoding; 3] = [Encoding::Br, Encoding::Zstd, Encoding::Gzip];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Br => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip"
+        }
+    }
+
+    /// The suffix of a precompressed sibling.
+    pub fn ext(self) -> &'static str {
+        match self {
+            Encoding::Br => ".br",
+            Encoding::Zstd => ".zst",
+            Encoding::Gzip => ".gz"
+        }
+    }
+
+    pub fn header(self) -> HeaderValue {
+        HeaderValue::from_static(self.name())
+    }
+}
+
+/// The encodings `Accept-Encoding` allows, best first.
+pub fn accepted(map: &HeaderMap) -> SmallVec<[Encoding; 3]> {
+    let mut quality = [None; 3];
+    let mut any = None;
+
+    let items = map.get_all(ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+
+    for item in items {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let q = params
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map_or(1.0, |q| q.trim().parse::<f32>().unwrap_or(0.0));
+
+        if name == "*" {
+            any = Some(q);
+        } else if let Some(i) = Encoding::ALL.iter()
+            .position(|encoding| name.eq_ignore_ascii_case(encoding.name()))
+        {
+            quality[i] = Some(q);
+        } else if name.eq_ignore_ascii_case("x-gzip") {
+            quality[2] = Some(q);
+        }
+    }
+
+    let mut list = Encoding::ALL.iter()
+        .zip(quality)
+        .filter_map(|(&encoding, q)| Some((encoding, q.or(any)?)))
+        .filter(|&(_, q)| q > 0.0)
+        .collect::<SmallVec<[_; 3]>>();
+
+    // stable, so equal ones keep our preference
+    list.sort_by(|(_, x), (_, y)| y.partial_cmp(x).unwrap_or(Ordering::Equal));
+    list.into_iter().map(|(encoding, _)| encoding).collect()
+}
+
+/// Compressing bodies as they are sent, for files without a precompressed sibling
+/// and for directory listings.
+#[derive(Default)]
+pub struct Compression {
+    /// smallest file compressed, in bytes.
+    pub min_size: u64,
+
+    /// largest file compressed, in bytes.
+    pub max_size: u64,
+
+    /// where compressed files are kept by ETag, `None` compresses every time.
+    pub cache: Option<PathBuf>,
+
+    /// most bytes kept in the cache, the oldest files go first.
+    pub cache_max: u64,
+
+    cache_used: AtomicU64,
+    trimming: Mutex<()>
+}
+
+impl Compression {
+    /// Whether a file of `mime` and `length` is worth compressing.
+    pub fn wants(&self, mime: &Mime, length: u64) -> bool {
+        (self.min_size..=self.max_size).contains(&length) && compressible(mime)
+    }
+
+    /// The cached file for `tag`, if there is one.
+    pub fn cached(&self, tag: &str) -> Option<(PathBuf, fs::Metadata)> {
+        let path = self.cache.as_ref()?.join(tag);
+        let metadata = path.metadata().ok()?;
+        Some((path, metadata))
+    }
+
+    /// Remove what an earlier run left half written, and trim the cache to `cache_max`.
+    pub fn clean(&self) -> io::Result<()> {
+        let dir = match self.cache.as_ref() {
+            Some(dir) => dir,
+            None => return Ok(())
+        };
+
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            if is_tmp(&entry) && entry.file_type()?.is_file() {
+                fs::remove_file(entry.path())?;
+            }
+        }
+
+        self.trim()
+    }
+
+    /// Count a file of `len` bytes just added to the cache.
+    fn added(&self, len: u64) -> io::Result<()> {
+        let used = self.cache_used.fetch_add(len, atomic::Ordering::Relaxed) + len;
+        if used > self.cache_max {
+            self.trim()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Remove the oldest cached files until the rest fit in `cache_max`.
+    fn trim(&self) -> io::Result<()> {
+        // whoever trims already will get it done
+        let _trimming = match self.trimming.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => return Ok(())
+        };
+        let dir = match self.cache.as_ref() {
+            Some(dir) => dir,
+            None => return Ok(())
+        };
+
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if !is_tmp(&entry) && metadata.is_file() {
+                files.push((metadata.modified()?, metadata.len(), entry.path()));
+            }
+        }
+
+        let mut used = files.iter().map(|&(_, len, _)| len).sum::<u64>();
+        files.sort_unstable_by_key(|&(modified, ..)| modified);
+
+        for (_, len, path) in files {
+            if used <= self.cache_max {
+                break;
+            }
+
+            match fs::remove_file(&path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => used -= len
+            }
+            debug!(?path, "compress/cache evict");
+        }
+
+        self.cache_used.store(used, atomic::Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// A file to keep a body compressed for `tag` in.
+    pub fn cache_file(self: &Arc<Self>, tag: &str) -> Option<CacheFile> {
+        let dir = self.cache.as_ref()?;
+        let suffix = thread_rng()
+            .sample_iter(&Alphanumeric)
+            .map(char::from)
+            .take(8)
+            .collect::<String>();
+        let tmp = dir.join(format!(".{}.{}", tag, suffix));
+
+        match fs::File::create(&tmp) {
+            Ok(fd) => Some(CacheFile { fd, tmp, path: dir.join(tag), compression: self.clone(), done: false }),
+            Err(err) => {
+                error!(?err, "compress/cache");
+                None
+            }
+        }
+    }
+}
+
+/// A body still being written, by its leading dot.
+fn is_tmp(entry: &fs::DirEntry) -> bool {
+    entry.file_name().as_encoded_bytes().starts_with(b".")
+}
+
+fn compressible(mime: &Mime) -> bool {
+    match (mime.type_(), mime.subtype().as_str()) {
+        (mime::TEXT, _) => true,
+        (mime::IMAGE, "svg") => true,
+        (mime::APPLICATION, "json" | "javascript" | "ecmascript" | "xml" | "wasm" | "toml"
+            | "yaml" | "x-yaml" | "x-sh" | "x-tex" | "rtf" | "postscript" | "x-ndjson") => true,
+        _ => mime.suffix().is_some_and(|suffix| suffix == mime::JSON || suffix == mime::XML)
+    }
+}
+
+/// A compressed body on its way into the cache, which only sees it once it is complete.
+pub struct CacheFile {
+    fd: fs::File,
+    tmp: PathBuf,
+    path: PathBuf,
+    compression: Arc<Compression>,
+    done: bool
+}
+
+impl CacheFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        block_in_place(|| self.fd.write_all(buf))
+    }
+
+    fn commit(mut self) -> io::Result<()> {
+        block_in_place(|| {
+            let len = self.fd.metadata()?.len();
+            fs::rename(&self.tmp, &self.path)?;
+            self.done = true;
+            self.compression.added(len)
+        })
+    }
+}
+
+impl Drop for CacheFile {
+    fn drop(&mut self) {
+        if !self.done {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+enum Inner {
+    Br(Box<BrotliEncoder<Vec<u8>>>),
+    Zstd(ZstdEncoder<Vec<u8>>),
+    Gzip(GzipEncoder<Vec<u8>>)
+}
+
+macro_rules! inner {
+    ( $inner:expr, $encoder:ident => $e:expr ) => {
+        match $inner {
+            Inner::Br($encoder) => $e,
+            Inner::Zstd($encoder) => $e,
+            Inner::Gzip($encoder) => $e
+        }
+    }
+}
+
+/// Compresses a body chunk by chunk.
+pub struct Encoder {
+    inner: Inner,
+    cache: Option<CacheFile>
+}
+
+impl Encoder {
+    pub fn new(encoding: Encoding) -> Encoder {
+        let inner = match encoding {
+            // the default 11 is far too slow to do for every request
+            Encoding::Br => Inner::Br(Box::new(BrotliEncoder::with_quality(Vec::new(), Level::Precise(4)))),
+            Encoding::Zstd => Inner::Zstd(ZstdEncoder::new(Vec::new())),
+            Encoding::Gzip => Inner::Gzip(GzipEncoder::new(Vec::new()))
+        };
+
+        Encoder { inner, cache: None }
+    }
+
+    /// Also write the compressed body to `cache`.
+    pub fn cache(mut self, cache: Option<CacheFile>) -> Encoder {
+        self.cache = cache;
+        self
+    }
+
+    /// Compress `buf`, returning what output is ready, which may be nothing.
+    pub async fn write(&mut self, buf: &[u8]) -> io::Result<Bytes> {
+        let out = inner!(&mut self.inner, encoder => {
+            encoder.write_all(buf).await?;
+            mem::take(encoder.get_mut())
+        });
+
+        self.tee(&out);
+        Ok(Bytes::from(out))
+    }
+
+    /// The rest of the output.
+    pub async fn finish(mut self) -> io::Result<Bytes> {
+        let out = inner!(&mut self.inner, encoder => {
+            encoder.shutdown().await?;
+            mem::take(encoder.get_mut())
+        });
+
+        self.tee(&out);
+        if let Some(Err(err)) = self.cache.take().map(CacheFile::commit) {
+            error!(?err, "compress/cache");
+        }
+
+        Ok(Bytes::from(out))
+    }
+
+    /// A cache that fails to write is dropped, the body goes on.
+    fn tee(&mut self, buf: &[u8]) {
+        if let Some(Err(err)) = self.cache.as_mut().map(|cache| cache.write(buf)) {
+            error!(?err, "compress/cache");
+            self.cache = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ env, process };
+    use std::time::{ Duration, SystemTime };
+    use http::HeaderValue;
+    use super::*;
+
+    fn accept(values: &[&'static str]) -> Vec<Encoding> {
+        let mut map = HeaderMap::new();
+        for &value in values {
+            map.append(ACCEPT_ENCODING, HeaderValue::from_static(value));
+        }
+        accepted(&map).into_vec()
+    }
+
+    #[test]
+    fn accept_encoding() {
+        use Encoding::*;
+
+        assert_eq!(accept(&[]), []);
+        assert_eq!(accept(&["identity"]), []);
+        assert_eq!(accept(&["gzip, deflate, br"]), [Br, Gzip]);
+        assert_eq!(accept(&["gzip;q=1.0, br;q=0.5, zstd;q=0.8"]), [Gzip, Zstd, Br]);
+        assert_eq!(accept(&["gzip", "ZSTD ; q=0.9"]), [Gzip, Zstd]);
+        assert_eq!(accept(&["br;q=0, gzip"]), [Gzip]);
+        assert_eq!(accept(&["x-gzip"]), [Gzip]);
+        assert_eq!(accept(&["br;q=bad, gzip"]), [Gzip]);
+
+        // ties keep our preference
+        assert_eq!(accept(&["gzip, zstd, br"]), [Br, Zstd, Gzip]);
+        assert_eq!(accept(&["gzip;q=0.5, br;q=0.5"]), [Br, Gzip]);
+
+        // `*` stands in for whatever isn't named
+        assert_eq!(accept(&["*"]), [Br, Zstd, Gzip]);
+        assert_eq!(accept(&["gzip;q=0.2, *;q=0.5"]), [Br, Zstd, Gzip]);
+        assert_eq!(accept(&["*;q=0.5, zstd;q=0"]), [Br, Gzip]);
+        assert_eq!(accept(&["*;q=0, gzip"]), [Gzip]);
+    }
+
+    fn cache(name: &str, max: u64) -> Arc<Compression> {
+        let dir = env::temp_dir().join(format!("webdir-cache-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        Arc::new(Compression { cache: Some(dir), cache_max: max, ..Compression::default() })
+    }
+
+    fn names(compression: &Compression) -> Vec<String> {
+        let mut names = fs::read_dir(compression.cache.as_ref().unwrap()).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// A cached file of `len` bytes, `age` seconds old.
+    fn put(compression: &Compression, name: &str, len: usize, age: u64) {
+        let path = compression.cache.as_ref().unwrap().join(name);
+        fs::write(&path, vec![0; len]).unwrap();
+        let fd = fs::File::options().write(true).open(&path).unwrap();
+        fd.set_modified(SystemTime::now() - Duration::from_secs(age)).unwrap();
+    }
+
+    #[test]
+    fn trim_oldest_first() {
+        let compression = cache("trim", 250);
+        put(&compression, "old", 100, 30);
+        put(&compression, "mid", 100, 20);
+        put(&compression, "new", 100, 10);
+        put(&compression, ".writing", 1000, 40);
+
+        compression.trim().unwrap();
+        assert_eq!(names(&compression), [".writing", "mid", "new"]);
+        assert_eq!(compression.cache_used.load(atomic::Ordering::Relaxed), 200);
+
+        fs::remove_dir_all(compression.cache.as_ref().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn clean_leftovers() {
+        let compression = cache("clean", 150);
+        put(&compression, "old", 100, 20);
+        put(&compression, "new", 100, 10);
+        put(&compression, ".new.a1b2c3d4", 50, 0);
+
+        compression.clean().unwrap();
+        assert_eq!(names(&compression), ["new"]);
+        assert_eq!(compression.cache_used.load(atomic::Ordering::Relaxed), 100);
+
+        fs::remove_dir_all(compression.cache.as_ref().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn cache_file_commit_or_drop() {
+        let compression = cache("file", 150);
+
+        let mut file = compression.cache_file("dropped").unwrap();
+        file.write(b"half a body").unwrap();
+        assert_eq!(names(&compression).len(), 1);
+        drop(file);
+        assert!(names(&compression).is_empty());
+
+        let mut file = compression.cache_file("kept").unwrap();
+        file.write(&[0; 100]).unwrap();
+        assert!(compression.cached("kept").is_none());
+        file.commit().unwrap();
+        assert_eq!(names(&compression), ["kept"]);
+        assert_eq!(compression.cached("kept").unwrap().1.len(), 100);
+        assert_eq!(compression.cache_used.load(atomic::Ordering::Relaxed), 100);
+
+        // past the limit, the older one goes
+        put(&compression, "kept", 100, 10);
+        let mut file = compression.cache_file("newer").unwrap();
+        file.write(&[0; 100]).unwrap();
+        file.commit().unwrap();
+        assert_eq!(names(&compression), ["newer"]);
+        assert_eq!(compression.cache_used.load(atomic::Ordering::Relaxed), 100);
+
+        fs::remove_dir_all(compression.cache.as_ref().unwrap()).unwrap();
+    }
+}
@@ -2,37 +2,82 @@ use std::fs;
 use std::path::Path;
 use bytes::Bytes;
 use std::io::{ self, Read, Seek };
+#[cfg(unix)]
+use std::os::unix::io::AsFd;
 use tokio::task::block_in_place;
+use crate::symlink::SymlinkPolicy;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring;
 
 
 pub struct File {
-    inner: fs::File,
-    buf: Vec<u8>,
+    inner: Inner
+}
+
+enum Inner {
+    Std(fs::File, Vec<u8>),
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    Uring(uring::File)
 }
 
 impl File {
-    pub async fn open(path: &Path) -> io::Result<File> {
+    /// Open `path` below `root`, if it still passes `policy` once open.
+    pub async fn open(path: &Path, root: &Path, policy: SymlinkPolicy) -> io::Result<File> {
+        let fd = File::open_trusted(path).await?;
+        block_in_place(|| fd.check(root, path, policy))?;
+        Ok(fd)
+    }
+
+    /// Open a file of our own, such as a cached compressed copy, which no symlink policy applies to.
+    pub async fn open_trusted(path: &Path) -> io::Result<File> {
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(ring) = uring::ring() {
+            return Ok(File { inner: Inner::Uring(ring.open(path).await?) });
+        }
+
         block_in_place(|| {
             let fd = fs::File::open(path)?;
             Ok(File {
-                inner: fd,
-                buf: vec![0; 1 << 16],
+                inner: Inner::Std(fd, vec![0; 1 << 16])
             })
         })
     }
 
+    #[cfg(unix)]
+    fn check(&self, root: &Path, path: &Path, policy: SymlinkPolicy) -> io::Result<()> {
+        let fd = match &self.inner {
+            Inner::Std(fd, _) => fd.as_fd(),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Inner::Uring(fd) => fd.as_fd()
+        };
+        policy.check_opened(root, path, fd)
+    }
+
+    #[cfg(not(unix))]
+    fn check(&self, root: &Path, path: &Path, policy: SymlinkPolicy) -> io::Result<()> {
+        policy.check(root, path)
+    }
+
     pub async fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
-        block_in_place(|| self.inner.seek(pos))
+        match &mut self.inner {
+            Inner::Std(fd, _) => block_in_place(|| fd.seek(pos)),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Inner::Uring(fd) => fd.seek(pos).await
+        }
     }
 
     pub async fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
-        block_in_place(|| {
-            let n = self.inner.read(&mut self.buf)?;
-            Ok(if n == 0 {
-                None
-            } else {
-                Some(Bytes::copy_from_slice(&self.buf[..n]))
-            })
-        })
+        match &mut self.inner {
+            Inner::Std(fd, buf) => block_in_place(|| {
+                let n = fd.read(buf)?;
+                Ok(if n == 0 {
+                    None
+                } else {
+                    Some(Bytes::copy_from_slice(&buf[..n]))
+                })
+            }),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            Inner::Uring(fd) => fd.next_chunk().await
+        }
     }
 }
@@ -0,0 +1,210 @@
+use std::{ fs, io };
+use std::sync::{ Arc, Mutex };
+use std::ffi::OsStr;
+use std::path::{ Path, PathBuf };
+use std::time::SystemTime;
+use std::collections::HashMap;
+use ignore::gitignore::{ Gitignore, GitignoreBuilder };
+
+
+/// Per-directory ignore file, read with gitignore syntax.
+pub const IGNORE_FILE: &str = ".webdirignore";
+
+/// Hides files from listings and direct requests.
+///
+/// Rules are checked from the deepest `.webdirignore` up to the root one,
+/// then the global rules, the first match wins.
+pub struct Filter {
+    globs: Gitignore,
+
+    /// parsed ignore files by directory.
+    cache: Mutex<HashMap<PathBuf, Cached>>
+}
+
+/// An ignore file as it was when parsed.
+struct Cached {
+    modified: Option<SystemTime>,
+    len: u64,
+    rules: Arc<Gitignore>
+}
+
+impl Filter {
+    /// `hidden` adds a built-in `.*` rule below `globs`,
+    /// so dotfiles can still be whitelisted with `!name`.
+    pub fn new(root: &Path, hidden: bool, globs: &[String]) -> io::Result<Filter> {
+        let mut builder = GitignoreBuilder::new(root);
+
+        if hidden {
+            builder.add_line(None, ".*").map_err(invalid)?;
+        }
+
+        for glob in globs {
+            builder.add_line(None, glob).map_err(invalid)?;
+        }
+
+        let globs = builder.build().map_err(invalid)?;
+        Ok(Filter { globs, cache: Mutex::default() })
+    }
+
+    /// Whether `path`, which lies under `root`, or any of its parents is ignored.
+    pub fn is_ignored(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        let relative = match path.strip_prefix(root) {
+            Ok(relative) => relative,
+            Err(_) => return true
+        };
+
+        let mut levels = vec![self.load(root)];
+        let mut sum = root.to_path_buf();
+        let mut components = relative.components().peekable();
+
+        while let Some(next) = components.next() {
+            sum.push(next);
+            let is_last = components.peek().is_none();
+
+            if self.matched(&levels, &sum, !is_last || is_dir) {
+                return true;
+            }
+
+            if !is_last {
+                levels.push(self.load(&sum));
+            }
+        }
+
+        false
+    }
+
+    /// The rules of `dir`, parsed again only when its ignore file changed.
+    fn load(&self, dir: &Path) -> Option<Arc<Gitignore>> {
+        let path = dir.join(IGNORE_FILE);
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => {
+                self.cache.lock().unwrap().remove(dir);
+                return None;
+            }
+        };
+        let modified = metadata.modified().ok();
+        let len = metadata.len();
+
+        if let Some(cached) = self.cache.lock().unwrap().get(dir) {
+            if cached.modified == modified && cached.len == len {
+                return Some(cached.rules.clone());
+            }
+        }
+
+        let rules = Arc::new(load(dir, &path)?);
+        self.cache.lock().unwrap().insert(dir.to_path_buf(), Cached { modified, len, rules: rules.clone() });
+        Some(rules)
+    }
+
+    fn matched(&self, levels: &[Option<Arc<Gitignore>>], path: &Path, is_dir: bool) -> bool {
+        if path.file_name() == Some(OsStr::new(IGNORE_FILE)) {
+            return true;
+        }
+
+        levels.iter()
+            .rev()
+            .flatten()
+            .map(|rules| &**rules)
+            .chain(Some(&self.globs))
+            .map(|rules| rules.matched(path, is_dir))
+            .find(|m| !m.is_none())
+            .is_some_and(|m| m.is_ignore())
+    }
+}
+
+/// The rules that apply to the entries of one directory.
+pub struct DirFilter {
+    filter: Arc<Filter>,
+    dir: PathBuf,
+    levels: Vec<Option<Arc<Gitignore>>>
+}
+
+impl DirFilter {
+    /// `dir` must lie under `root` and must not be ignored itself.
+    pub fn new(filter: Arc<Filter>, root: &Path, dir: &Path) -> DirFilter {
+        let mut levels = vec![filter.load(root)];
+        let mut sum = root.to_path_buf();
+
+        if let Ok(relative) = dir.strip_prefix(root) {
+            for next in relative.components() {
+                sum.push(next);
+                levels.push(filter.load(&sum));
+            }
+        }
+
+        DirFilter { filter, dir: dir.to_path_buf(), levels }
+    }
+
+    pub fn is_ignored(&self, name: &OsStr, is_dir: bool) -> bool {
+        self.filter.matched(&self.levels, &self.dir.join(name), is_dir)
+    }
+}
+
+fn load(dir: &Path, path: &Path) -> Option<Gitignore> {
+    let mut builder = GitignoreBuilder::new(dir);
+    if let Some(err) = builder.add(path) {
+        warn!(?err, ?path, "filter/load");
+    }
+
+    match builder.build() {
+        Ok(rules) => Some(rules),
+        Err(err) => {
+            warn!(?err, ?path, "filter/load");
+            None
+        }
+    }
+}
+
+fn invalid(err: ignore::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ env, process };
+    use super::*;
+
+    #[test]
+    fn hidden_and_ignored() {
+        let root = env::temp_dir().join(format!("webdir-filter-{}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sub")).unwrap();
+        let root = root.canonicalize().unwrap();
+        fs::write(root.join(IGNORE_FILE), "*.log\n").unwrap();
+        fs::write(root.join("sub").join(IGNORE_FILE), "!keep.log\nsecret.txt\n").unwrap();
+
+        let filter = Arc::new(Filter::new(&root, true, &["!.well-known".into(), "*.bak".into()]).unwrap());
+        let ignored = |path: &str| filter.is_ignored(&root, &root.join(path), false);
+
+        assert!(!ignored("index.html"));
+        assert!(ignored(".env"));
+        assert!(ignored(".git/config"));
+        assert!(!ignored(".well-known/acme"));
+        assert!(ignored("old.bak"));
+        assert!(ignored(IGNORE_FILE));
+        assert!(ignored("sub/.webdirignore"));
+
+        // the nearest ignore file wins
+        assert!(ignored("a.log"));
+        assert!(ignored("sub/a.log"));
+        assert!(!ignored("sub/keep.log"));
+        assert!(ignored("sub/secret.txt"));
+        assert!(!ignored("secret.txt"));
+
+        let dir = DirFilter::new(filter.clone(), &root, &root.join("sub"));
+        assert!(dir.is_ignored(OsStr::new("a.log"), false));
+        assert!(!dir.is_ignored(OsStr::new("keep.log"), false));
+        assert!(dir.is_ignored(OsStr::new(".hidden"), true));
+
+        // read again once it changed
+        fs::write(root.join("sub").join(IGNORE_FILE), "!*.log\n").unwrap();
+        assert!(!ignored("sub/a.log"));
+        assert!(!ignored("sub/secret.txt"));
+
+        fs::remove_file(root.join("sub").join(IGNORE_FILE)).unwrap();
+        assert!(ignored("sub/keep.log"));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,297 @@
+//! What a trusted reverse proxy tells us about the client, from `Forwarded` (RFC 7239)
+//! or the `X-Forwarded-*` headers.
+
+use std::str::FromStr;
+use std::net::{ IpAddr, SocketAddr };
+use http::HeaderMap;
+use http::header::{ FORWARDED, HeaderName };
+
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_PREFIX: HeaderName = HeaderName::from_static("x-forwarded-prefix");
+
+/// A peer whose forwarding headers are believed: `<ip>[/<prefix len>]`, or `unix` for Unix sockets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrustedProxy {
+    Net(IpAddr, u8),
+    Unix
+}
+
+impl FromStr for TrustedProxy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "unix" {
+            return Ok(TrustedProxy::Unix);
+        }
+
+        let (ip, len) = match s.split_once('/') {
+            Some((ip, len)) => (ip, Some(len)),
+            None => (s, None)
+        };
+        let ip = ip.parse::<IpAddr>()
+            .map_err(|err| format!("bad trusted proxy {}: {}", s, err))?
+            .to_canonical();
+        let max = if ip.is_ipv4() { 32 } else { 128 };
+        let len = match len {
+            Some(len) => len.parse::<u8>()
+                .ok()
+                .filter(|&len| len <= max)
+                .ok_or_else(|| format!("bad prefix length: {}", s))?,
+            None => max
+        };
+
+        Ok(TrustedProxy::Net(ip, len))
+    }
+}
+
+impl TrustedProxy {
+    /// `addr` is `None` for Unix sockets.
+    fn contains(&self, addr: Option<IpAddr>) -> bool {
+        match (self, addr.map(|ip| ip.to_canonical())) {
+            (TrustedProxy::Unix, None) => true,
+            (TrustedProxy::Net(IpAddr::V4(net), len), Some(IpAddr::V4(ip))) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(*len)).unwrap_or(0);
+                u32::from(*net) & mask == u32::from(ip) & mask
+            },
+            (TrustedProxy::Net(IpAddr::V6(net), len), Some(IpAddr::V6(ip))) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(*len)).unwrap_or(0);
+                u128::from(*net) & mask == u128::from(ip) & mask
+            },
+            _ => false
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct TrustedProxies(pub Vec<TrustedProxy>);
+
+impl TrustedProxies {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, addr: Option<IpAddr>) -> bool {
+        self.0.iter().any(|proxy| proxy.contains(addr))
+    }
+}
+
+/// The client as the proxies in front of us saw it.
+#[derive(Debug, Default)]
+pub struct Forwarded {
+    /// the first address in the chain that isn't a trusted proxy, port 0 if it wasn't given.
+    pub client: Option<SocketAddr>,
+
+    /// whether the client connected over HTTPS.
+    pub secure: Option<bool>,
+
+    /// the path prefix the proxy strips before passing the request on, without a trailing `/`.
+    pub prefix: Option<String>
+}
+
+impl Forwarded {
+    /// Only call this for requests from a trusted proxy, the headers are easy to forge.
+    pub fn from_headers(headers: &HeaderMap, trusted: &TrustedProxies) -> Forwarded {
+        let mut forwarded = Forwarded {
+            prefix: last_value(headers, &X_FORWARDED_PREFIX).and_then(normalize_prefix),
+            ..Forwarded::default()
+        };
+
+        let elements = list(headers, &FORWARDED)
+            .map(|element| {
+                let mut node = None;
+                let mut proto = None;
+                for pair in element.split(';') {
+                    match pair.split_once('=') {
+                        Some((key, value)) if key.trim().eq_ignore_ascii_case("for") =>
+                            node = Some(value.trim().trim_matches('"')),
+                        Some((key, value)) if key.trim().eq_ignore_ascii_case("proto") =>
+                            proto = Some(value.trim().trim_matches('"')),
+                        _ => ()
+                    }
+                }
+                (node, proto)
+            })
+            .collect::<Vec<_>>();
+
+        if !elements.is_empty() {
+            let nodes = elements.iter().map(|(node, _)| node.and_then(parse_node)).collect::<Vec<_>>();
+            let i = client_index(&nodes, trusted);
+            forwarded.client = nodes[i];
+            forwarded.secure = elements[i].1.map(|proto| proto.eq_ignore_ascii_case("https"));
+        } else {
+            let nodes = list(headers, &X_FORWARDED_FOR).map(parse_node).collect::<Vec<_>>();
+            if !nodes.is_empty() {
+                forwarded.client = nodes[client_index(&nodes, trusted)];
+            }
+
+            // the last proxy appends or replaces it, so that is the one we trust
+            forwarded.secure = last_value(headers, &X_FORWARDED_PROTO)
+                .map(|proto| proto.eq_ignore_ascii_case("https"));
+        }
+
+        forwarded
+    }
+}
+
+/// Walk back from the nearest hop, skipping trusted proxies.
+fn client_index(nodes: &[Option<SocketAddr>], trusted: &TrustedProxies) -> usize {
+    nodes.iter()
+        .rposition(|node| match node {
+            Some(addr) => !trusted.contains(Some(addr.ip())),
+            None => true
+        })
+        .unwrap_or(0)
+}
+
+/// `192.0.2.1`, `"[2001:db8::1]:4711"` and friends, `unknown` or an obfuscated name is `None`.
+fn parse_node(node: &str) -> Option<SocketAddr> {
+    let node = node.trim();
+
+    node.parse::<SocketAddr>().ok()
+        .or_else(|| node.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0)))
+        .or_else(|| {
+            let ip = node.strip_prefix('[')?.strip_suffix(']')?;
+            ip.parse::<IpAddr>().ok().map(|ip| SocketAddr::new(ip, 0))
+        })
+}
+
+fn list<'a>(headers: &'a HeaderMap, name: &HeaderName) -> impl Iterator<Item = &'a str> {
+    headers.get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+fn last_value<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Option<&'a str> {
+    list(headers, name).last()
+}
+
+/// A prefix is taken as an absolute path of visible ASCII, it ends up in `Location` and links.
+fn normalize_prefix(prefix: &str) -> Option<String> {
+    if !prefix.bytes().all(|b| b.is_ascii_graphic()) {
+        return None;
+    }
+
+    // `//host` or a `..` left in would lead off the site
+    let mut segments = Vec::new();
+    for segment in prefix.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." => {
+                segments.pop();
+            },
+            segment => segments.push(segment)
+        }
+    }
+
+    Some(segments.iter().map(|segment| format!("/{}", segment)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+    use super::*;
+
+    fn headers(pairs: &[(&HeaderName, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for &(name, value) in pairs {
+            map.append(name.clone(), HeaderValue::from_static(value));
+        }
+        map
+    }
+
+    fn trusted(proxies: &[&str]) -> TrustedProxies {
+        TrustedProxies(proxies.iter().map(|proxy| proxy.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn trusted_proxy_nets() {
+        let proxies = trusted(&["10.0.0.0/8", "2001:db8::/32", "192.0.2.1", "unix"]);
+
+        assert!(proxies.contains(Some("10.1.2.3".parse().unwrap())));
+        assert!(proxies.contains(Some("192.0.2.1".parse().unwrap())));
+        assert!(proxies.contains(Some("::ffff:10.0.0.1".parse().unwrap())));
+        assert!(proxies.contains(Some("2001:db8:1::1".parse().unwrap())));
+        assert!(proxies.contains(None));
+        assert!(!proxies.contains(Some("11.0.0.1".parse().unwrap())));
+        assert!(!proxies.contains(Some("192.0.2.2".parse().unwrap())));
+        assert!(!proxies.contains(Some("2001:db9::1".parse().unwrap())));
+        assert!(!trusted(&["0.0.0.0/0"]).contains(None));
+        assert!(trusted(&["0.0.0.0/0"]).contains(Some("203.0.113.9".parse().unwrap())));
+
+        assert!("10.0.0.0/33".parse::<TrustedProxy>().is_err());
+        assert!("proxy.example".parse::<TrustedProxy>().is_err());
+    }
+
+    #[test]
+    fn forwarded_header() {
+        let map = headers(&[
+            (&FORWARDED, r#"for="[2001:db8::1]:4711";proto=https, for=192.0.2.60;proto=http"#),
+            (&FORWARDED, r#"For="10.0.0.2";by=10.0.0.3"#)
+        ]);
+
+        // the proxy at 10.0.0.2 is ours, the one at 192.0.2.60 isn't
+        let forwarded = Forwarded::from_headers(&map, &trusted(&["10.0.0.0/8"]));
+        assert_eq!(forwarded.client, Some("192.0.2.60:0".parse().unwrap()));
+        assert_eq!(forwarded.secure, Some(false));
+
+        let forwarded = Forwarded::from_headers(&map, &trusted(&["10.0.0.0/8", "192.0.2.60"]));
+        assert_eq!(forwarded.client, Some("[2001:db8::1]:4711".parse().unwrap()));
+        assert_eq!(forwarded.secure, Some(true));
+
+        let map = headers(&[(&FORWARDED, r#"for="[2001:db8::2]""#)]);
+        let forwarded = Forwarded::from_headers(&map, &trusted(&[]));
+        assert_eq!(forwarded.client, Some("[2001:db8::2]:0".parse().unwrap()));
+
+        // an obfuscated hop hides whoever is behind it
+        let map = headers(&[(&FORWARDED, "for=192.0.2.1, for=_hidden, for=10.0.0.2")]);
+        let forwarded = Forwarded::from_headers(&map, &trusted(&["10.0.0.0/8"]));
+        assert_eq!(forwarded.client, None);
+    }
+
+    #[test]
+    fn x_forwarded_headers() {
+        let map = headers(&[
+            (&X_FORWARDED_FOR, "203.0.113.1, 198.51.100.7"),
+            (&X_FORWARDED_FOR, "10.0.0.2"),
+            (&X_FORWARDED_PROTO, "http, HTTPS")
+        ]);
+
+        let forwarded = Forwarded::from_headers(&map, &trusted(&["10.0.0.0/8"]));
+        assert_eq!(forwarded.client, Some("198.51.100.7:0".parse().unwrap()));
+        assert_eq!(forwarded.secure, Some(true));
+
+        let forwarded = Forwarded::from_headers(&map, &trusted(&["10.0.0.0/8", "198.51.100.0/24"]));
+        assert_eq!(forwarded.client, Some("203.0.113.1:0".parse().unwrap()));
+
+        // every hop trusted, the first is as far back as we can see
+        let forwarded = Forwarded::from_headers(&map, &trusted(&["0.0.0.0/0"]));
+        assert_eq!(forwarded.client, Some("203.0.113.1:0".parse().unwrap()));
+
+        // Forwarded wins over X-Forwarded-For
+        let mut map = map;
+        map.insert(FORWARDED, HeaderValue::from_static("for=192.0.2.9"));
+        let forwarded = Forwarded::from_headers(&map, &trusted(&["10.0.0.0/8"]));
+        assert_eq!(forwarded.client, Some("192.0.2.9:0".parse().unwrap()));
+        assert_eq!(forwarded.secure, None);
+    }
+
+    #[test]
+    fn prefix() {
+        assert_eq!(normalize_prefix("/files/").as_deref(), Some("/files"));
+        assert_eq!(normalize_prefix("files").as_deref(), Some("/files"));
+        assert_eq!(normalize_prefix("//evil.com").as_deref(), Some("/evil.com"));
+        assert_eq!(normalize_prefix("/a/./b/../c").as_deref(), Some("/a/c"));
+        assert_eq!(normalize_prefix("/../../x").as_deref(), Some("/x"));
+        assert_eq!(normalize_prefix("/").as_deref(), Some(""));
+        assert_eq!(normalize_prefix("/a b"), None);
+        assert_eq!(normalize_prefix("/caf\u{e9}"), None);
+
+        let map = headers(&[(&X_FORWARDED_PREFIX, "/outer, /inner/")]);
+        assert_eq!(Forwarded::from_headers(&map, &trusted(&[])).prefix.as_deref(), Some("/inner"));
+    }
+}
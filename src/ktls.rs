@@ -0,0 +1,499 @@
+//! Kernel TLS, so HTTPS bodies can be sent with `sendfile(2)` too.
+//!
+//! After the rustls handshake the traffic keys are installed on the socket,
+//! which then reads and writes plaintext like any other.
+
+use std::{ fs, io, mem };
+use std::pin::Pin;
+use std::io::{ IoSlice, Read };
+use std::net::{ Ipv4Addr, TcpListener, TcpStream };
+use std::os::unix::io::{ AsRawFd, RawFd };
+use std::sync::OnceLock;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::task::{ Context, Poll };
+use bytes::{ Buf, Bytes };
+use tokio::io::{ AsyncRead, AsyncWrite, ReadBuf };
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::rustls::{ CipherSuite, ProtocolVersion, ConnectionTrafficSecrets, ExtractedSecrets };
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use crate::stream::Cork;
+use crate::sendfile::AsyncSendfile;
+
+
+// linux/tls.h
+const TLS_TX: libc::c_int = 1;
+const TLS_RX: libc::c_int = 2;
+const TLS_SET_RECORD_TYPE: libc::c_int = 1;
+const TLS_1_2_VERSION: u16 = 0x0303;
+const TLS_1_3_VERSION: u16 = 0x0304;
+const TLS_CIPHER_AES_GCM_128: u16 = 51;
+const TLS_CIPHER_AES_GCM_256: u16 = 52;
+const TLS_CIPHER_CHACHA20_POLY1305: u16 = 54;
+const TLS_RECORD_ALERT: u8 = 21;
+
+#[repr(C)]
+struct CryptoInfo {
+    version: u16,
+    cipher_type: u16
+}
+
+#[repr(C)]
+struct AesGcm128 {
+    info: CryptoInfo,
+    iv: [u8; 8],
+    key: [u8; 16],
+    salt: [u8; 4],
+    rec_seq: [u8; 8]
+}
+
+#[repr(C)]
+struct AesGcm256 {
+    info: CryptoInfo,
+    iv: [u8; 8],
+    key: [u8; 32],
+    salt: [u8; 4],
+    rec_seq: [u8; 8]
+}
+
+#[repr(C)]
+struct Chacha20Poly1305 {
+    info: CryptoInfo,
+    iv: [u8; 12],
+    key: [u8; 32],
+    rec_seq: [u8; 8]
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Cipher {
+    Aes128Gcm,
+    Aes256Gcm,
+    Chacha20Poly1305
+}
+
+impl Cipher {
+    const ALL: [Cipher; 3] = [Cipher::Aes128Gcm, Cipher::Aes256Gcm, Cipher::Chacha20Poly1305];
+
+    fn of(suite: CipherSuite) -> Option<Cipher> {
+        match suite {
+            CipherSuite::TLS13_AES_128_GCM_SHA256
+                | CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256
+                | CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256 => Some(Cipher::Aes128Gcm),
+            CipherSuite::TLS13_AES_256_GCM_SHA384
+                | CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384
+                | CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384 => Some(Cipher::Aes256Gcm),
+            CipherSuite::TLS13_CHACHA20_POLY1305_SHA256
+                | CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256
+                | CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256 => Some(Cipher::Chacha20Poly1305),
+            _ => None
+        }
+    }
+
+    fn key_len(self) -> usize {
+        match self {
+            Cipher::Aes128Gcm => 16,
+            Cipher::Aes256Gcm | Cipher::Chacha20Poly1305 => 32
+        }
+    }
+}
+
+const VERSIONS: [u16; 2] = [TLS_1_2_VERSION, TLS_1_3_VERSION];
+
+/// Which version and cipher pairs the kernel takes keys for.
+///
+/// Keys can't be handed back to rustls once extracted, so this is found out up
+/// front on a loopback connection, and a pair that fails anyway is dropped.
+static SUPPORTED: OnceLock<[[AtomicBool; 3]; 2]> = OnceLock::new();
+
+fn supported(version: u16, cipher: Cipher) -> &'static AtomicBool {
+    let supported = SUPPORTED.get_or_init(|| {
+        let supported = VERSIONS.map(|version| Cipher::ALL.map(|cipher| {
+            AtomicBool::new(match probe(version, cipher) {
+                Ok(()) => true,
+                Err(err) if is_missing(&err) => false,
+                Err(err) => {
+                    info!(?err, version, ?cipher, "ktls/probe");
+                    false
+                }
+            })
+        }));
+
+        if supported.iter().flatten().all(|supported| !supported.load(Ordering::Relaxed)) {
+            warn!("ktls/unsupported");
+        }
+        supported
+    });
+
+    let version = VERSIONS.iter().position(|&v| v == version).unwrap_or_default();
+    let cipher = Cipher::ALL.iter().position(|&c| c == cipher).unwrap_or_default();
+    &supported[version][cipher]
+}
+
+/// Whether `err` says the kernel has no TLS at all, rather than something about the socket.
+fn is_missing(err: &io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::ENOENT | libc::ENOPROTOOPT))
+}
+
+/// Install dummy keys both ways on a fresh loopback connection.
+fn probe(version: u16, cipher: Cipher) -> io::Result<()> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    let _client = TcpStream::connect(listener.local_addr()?)?;
+    let (server, _) = listener.accept()?;
+    let fd = server.as_raw_fd();
+
+    setsockopt(fd, libc::SOL_TCP, libc::TCP_ULP, b"tls".as_ref())?;
+    let key = [0; 32];
+    let iv = [0; 12];
+    install(fd, TLS_TX, version, cipher, &key[..cipher.key_len()], &iv, 0)?;
+    install(fd, TLS_RX, version, cipher, &key[..cipher.key_len()], &iv, 0)
+}
+
+/// A socket the kernel can take TLS over.
+pub trait KernelSocket {
+    /// `None` if this isn't a TCP socket, or bytes were read from it that the kernel would never see.
+    fn kernel_fd(&self) -> Option<RawFd>;
+}
+
+/// A connection whose TLS records the kernel takes care of.
+pub struct Ktls<IO> {
+    io: IO,
+    fd: RawFd,
+
+    /// what rustls decrypted before we took over.
+    buf: Bytes,
+    closed: bool,
+    pub(crate) server_name: Option<String>,
+    pub(crate) alpn: Option<Vec<u8>>,
+    pub(crate) cert: Option<CertificateDer<'static>>
+}
+
+/// Hand `tls` to the kernel, or give it back if the kernel or connection can't.
+///
+/// Past the point of no return, failures are errors.
+pub fn setup<IO>(tls: TlsStream<Cork<IO>>) -> io::Result<Result<Ktls<IO>, TlsStream<Cork<IO>>>>
+where IO: AsyncRead + AsyncWrite + KernelSocket + Unpin
+{
+    let (io, conn) = tls.get_ref();
+    let fd = match io.kernel_fd() {
+        Some(fd) => fd,
+        None => return Ok(Err(tls))
+    };
+    let version = match conn.protocol_version() {
+        Some(ProtocolVersion::TLSv1_3) => TLS_1_3_VERSION,
+        Some(ProtocolVersion::TLSv1_2) => TLS_1_2_VERSION,
+        _ => return Ok(Err(tls))
+    };
+    let cipher = match conn.negotiated_cipher_suite().and_then(|suite| Cipher::of(suite.suite())) {
+        Some(cipher) => cipher,
+        None => return Ok(Err(tls))
+    };
+    let supported = supported(version, cipher);
+    if !supported.load(Ordering::Relaxed) || conn.wants_write() {
+        return Ok(Err(tls));
+    }
+
+    // failing leaves the socket as it was
+    if let Err(err) = setsockopt(fd, libc::SOL_TCP, libc::TCP_ULP, b"tls".as_ref()) {
+        if is_missing(&err) {
+            supported.store(false, Ordering::Relaxed);
+        }
+        debug!(?err, "ktls/ulp");
+        return Ok(Err(tls));
+    }
+
+    let (io, mut conn) = tls.into_inner();
+    let server_name = conn.server_name().map(Into::into);
+    let alpn = conn.alpn_protocol().map(<[u8]>::to_vec);
+    let cert = conn.peer_certificates()
+        .and_then(|certs| certs.first())
+        .map(|cert| cert.clone().into_owned());
+
+    let mut buf = Vec::new();
+    match conn.reader().read_to_end(&mut buf) {
+        Err(err) if err.kind() != io::ErrorKind::WouldBlock => return Err(err),
+        _ => ()
+    }
+
+    let ExtractedSecrets { tx, rx } = conn.dangerous_extract_secrets()
+        .map_err(io::Error::other)?;
+    let installed = install_secrets(fd, TLS_TX, version, tx)
+        .and_then(|_| install_secrets(fd, TLS_RX, version, rx));
+    if let Err(err) = installed {
+        // the probe was wrong, spare the next connections
+        supported.store(false, Ordering::Relaxed);
+        return Err(err);
+    }
+
+    Ok(Ok(Ktls {
+        io: io.into_inner(), fd,
+        buf: Bytes::from(buf),
+        closed: false,
+        server_name, alpn, cert
+    }))
+}
+
+fn install_secrets(fd: RawFd, direction: libc::c_int, version: u16, (seq, secrets): (u64, ConnectionTrafficSecrets))
+    -> io::Result<()>
+{
+    match secrets {
+        ConnectionTrafficSecrets::Aes128Gcm { key, iv } =>
+            install(fd, direction, version, Cipher::Aes128Gcm, key.as_ref(), iv.as_ref(), seq),
+        ConnectionTrafficSecrets::Aes256Gcm { key, iv } =>
+            install(fd, direction, version, Cipher::Aes256Gcm, key.as_ref(), iv.as_ref(), seq),
+        ConnectionTrafficSecrets::Chacha20Poly1305 { key, iv } =>
+            install(fd, direction, version, Cipher::Chacha20Poly1305, key.as_ref(), iv.as_ref(), seq),
+        _ => Err(io::Error::new(io::ErrorKind::Unsupported, "ktls cipher suite"))
+    }
+}
+
+fn install(fd: RawFd, direction: libc::c_int, version: u16, cipher: Cipher, key: &[u8], iv: &[u8], seq: u64)
+    -> io::Result<()>
+{
+    let rec_seq = seq.to_be_bytes();
+
+    // GCM takes the implicit part of the nonce as salt
+    match cipher {
+        Cipher::Aes128Gcm => {
+            let mut info = AesGcm128 {
+                info: CryptoInfo { version, cipher_type: TLS_CIPHER_AES_GCM_128 },
+                iv: [0; 8], key: [0; 16], salt: [0; 4], rec_seq
+            };
+            info.salt.copy_from_slice(&iv[..4]);
+            info.iv.copy_from_slice(&iv[4..]);
+            info.key.copy_from_slice(key);
+            setsockopt(fd, libc::SOL_TLS, direction, &info)
+        },
+        Cipher::Aes256Gcm => {
+            let mut info = AesGcm256 {
+                info: CryptoInfo { version, cipher_type: TLS_CIPHER_AES_GCM_256 },
+                iv: [0; 8], key: [0; 32], salt: [0; 4], rec_seq
+            };
+            info.salt.copy_from_slice(&iv[..4]);
+            info.iv.copy_from_slice(&iv[4..]);
+            info.key.copy_from_slice(key);
+            setsockopt(fd, libc::SOL_TLS, direction, &info)
+        },
+        Cipher::Chacha20Poly1305 => {
+            let mut info = Chacha20Poly1305 {
+                info: CryptoInfo { version, cipher_type: TLS_CIPHER_CHACHA20_POLY1305 },
+                iv: [0; 12], key: [0; 32], rec_seq
+            };
+            info.iv.copy_from_slice(iv);
+            info.key.copy_from_slice(key);
+            setsockopt(fd, libc::SOL_TLS, direction, &info)
+        }
+    }
+}
+
+fn setsockopt<T: ?Sized>(fd: RawFd, level: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd, level, name,
+            value as *const T as *const libc::c_void,
+            mem::size_of_val(value) as libc::socklen_t
+        )
+    };
+
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Best effort, the socket is about to go anyway.
+fn close_notify(fd: RawFd) {
+    let mut alert = [1u8, 0]; // warning, close_notify
+    let mut iov = libc::iovec { iov_base: alert.as_mut_ptr() as *mut _, iov_len: alert.len() };
+    let mut control = [0u64; 4];
+
+    unsafe {
+        let mut msg: libc::msghdr = mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut _;
+        msg.msg_controllen = libc::CMSG_SPACE(1) as _;
+
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_TLS;
+        (*cmsg).cmsg_type = TLS_SET_RECORD_TYPE;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(1) as _;
+        *libc::CMSG_DATA(cmsg) = TLS_RECORD_ALERT;
+
+        libc::sendmsg(fd, &msg, libc::MSG_DONTWAIT);
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for Ktls<IO> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.buf.is_empty() {
+            let len = this.buf.len().min(buf.remaining());
+            buf.put_slice(&this.buf[..len]);
+            this.buf.advance(len);
+            return Poll::Ready(Ok(()));
+        }
+
+        // the kernel fails plain reads of anything but application data,
+        // which is close_notify, or a key update we can't follow
+        match Pin::new(&mut this.io).poll_read(cx, buf) {
+            Poll::Ready(Err(err)) if err.raw_os_error() == Some(libc::EIO) => Poll::Ready(Ok(())),
+            poll => poll
+        }
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for Ktls<IO> {
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.closed {
+            this.closed = true;
+            close_notify(this.fd);
+        }
+
+        Pin::new(&mut this.io).poll_shutdown(cx)
+    }
+
+    #[inline]
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>])
+        -> Poll<io::Result<usize>>
+    {
+        Pin::new(&mut self.get_mut().io).poll_write_vectored(cx, bufs)
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+}
+
+impl<IO: AsyncSendfile + Unpin> AsyncSendfile for Ktls<IO> {
+    #[inline]
+    fn poll_sendfile(self: Pin<&mut Self>, cx: &mut Context<'_>, file: &fs::File, offset: u64, len: usize)
+        -> Poll<io::Result<usize>>
+    {
+        Pin::new(&mut self.get_mut().io).poll_sendfile(cx, file, offset, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ env, process };
+    use std::sync::Arc;
+    use std::convert::TryFrom;
+    use futures::future::poll_fn;
+    use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+    use tokio::net::{ TcpListener, TcpStream };
+    use tokio_rustls::{ TlsAcceptor, TlsConnector };
+    use tokio_rustls::rustls::{ ClientConfig, ServerConfig, RootCertStore, SupportedCipherSuite };
+    use tokio_rustls::rustls::crypto::{ CryptoProvider, ring as provider };
+    use tokio_rustls::rustls::pki_types::{ PrivateKeyDer, PrivatePkcs8KeyDer, ServerName };
+    use crate::listener::Socket;
+    use crate::stream::Stream;
+    use super::*;
+
+    /// Whether the kernel took the connection over, either way the bytes must arrive.
+    async fn roundtrip(suite: SupportedCipherSuite) -> bool {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(certified.signing_key.serialize_der()));
+
+        let mut server = ServerConfig::builder_with_provider(Arc::new(provider::default_provider()))
+            .with_safe_default_protocol_versions().unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key).unwrap();
+        server.enable_secret_extraction = true;
+
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let provider = CryptoProvider { cipher_suites: vec![suite], ..provider::default_provider() };
+        let client = ClientConfig::builder_with_provider(Arc::new(provider))
+            .with_protocol_versions(&[suite.version()]).unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connect = async {
+            let tcp = TcpStream::connect(addr).await.unwrap();
+            let name = ServerName::try_from("localhost").unwrap();
+            let mut tls = TlsConnector::from(Arc::new(client)).connect(name, tcp).await.unwrap();
+
+            // right behind the handshake, before the kernel has keys
+            tls.write_all(b"ping").await.unwrap();
+            tls.flush().await.unwrap();
+            tls
+        };
+        let accept = async {
+            let (tcp, _) = listener.accept().await.unwrap();
+            let acceptor = TlsAcceptor::from(Arc::new(server));
+            Stream::new(Socket::Tcp(tcp), Some(acceptor), true).await.unwrap()
+        };
+        let (mut client, server) = tokio::join!(connect, accept);
+
+        let mut server = server.into_ktls().unwrap();
+        let offloaded = matches!(server, Stream::Ktls(_));
+
+        let mut ping = [0; 4];
+        server.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+
+        let body = (0..1 << 17).map(|i| i as u8).collect::<Vec<u8>>();
+        if offloaded {
+            let path = env::temp_dir().join(format!("webdir-ktls-{}-{:?}", process::id(), suite.suite()));
+            fs::write(&path, &body).unwrap();
+            let file = fs::File::open(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+
+            let mut sent = 0;
+            while sent < body.len() {
+                sent += poll_fn(|cx| Pin::new(&mut server).poll_sendfile(cx, &file, sent as u64, body.len() - sent))
+                    .await
+                    .unwrap();
+            }
+        } else {
+            server.write_all(&body).await.unwrap();
+        }
+        server.shutdown().await.unwrap();
+
+        // fails without close_notify
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, body);
+
+        offloaded
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn every_suite() {
+        let mut offloaded = Vec::new();
+        // the test certificate has an ECDSA key
+        let rsa = [
+            CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+            CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+            CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256
+        ];
+        let suites = provider::default_provider().cipher_suites.into_iter()
+            .filter(|suite| !rsa.contains(&suite.suite()));
+
+        for suite in suites {
+            if roundtrip(suite).await {
+                offloaded.push(suite.suite());
+            }
+        }
+
+        // only the fallback runs on kernels without the tls module
+        eprintln!("ktls offloaded: {:?}", offloaded);
+    }
+}
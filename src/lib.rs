@@ -9,28 +9,311 @@ mod stream;
 mod process;
 mod file;
 mod body;
+mod symlink;
+mod filter;
+mod auth;
+mod redirect;
+mod listener;
+mod transfer;
+mod proxy;
+mod forwarded;
+mod timeout;
+mod limit;
+mod throttle;
+mod encoding;
+mod sendfile;
+#[cfg(target_os = "linux")]
+mod ktls;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+pub mod tls;
+#[cfg(unix)]
+pub mod systemd;
+#[cfg(unix)]
+pub mod upgrade;
 
-use std::io;
+use std::{ io, fmt, ptr };
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::path::Path;
+use std::collections::HashMap;
 use futures::future;
 use hyper::body::Incoming;
 use hyper::service::Service;
 use hyper::{ StatusCode, Request, Response};
+use http::{ HeaderValue, Version };
+use http::uri::Authority;
+use http::header::{ HOST, CONTENT_TYPE, STRICT_TRANSPORT_SECURITY, WWW_AUTHENTICATE };
+use tracing::{ field, Span };
 use crate::body::ResponseBody as Body;
 use crate::process::Process;
-use crate::utils::err_html;
+use crate::auth::Access;
+use crate::tls::{ ClientCert, Challenges };
+use crate::forwarded::Forwarded;
+use crate::utils::{ err_html, decode_path, resolve_path };
 pub use crate::stream::Stream as WebStream;
+pub use crate::symlink::SymlinkPolicy;
+pub use crate::filter::Filter;
+pub use crate::auth::{ Auth, Realm, Htpasswd };
+pub use crate::redirect::{ Redirect, hsts };
+pub use crate::listener::{ Addr, Listener, Socket };
+pub use crate::transfer::{ Transfers, Aborted };
+pub use crate::proxy::{ ProxyProtocol, Proxy, ProxyStream };
+pub use crate::forwarded::{ TrustedProxy, TrustedProxies };
+pub use crate::timeout::{ Timeout, Timeouts, TimeoutStream, InFlight, Responding };
+pub use crate::limit::{ Limits, IpPermit };
+pub use crate::throttle::{ Bucket, PathBuckets, Throttle };
+pub use crate::encoding::Compression;
+pub use crate::sendfile::{ Sendfile, SendfileStream, AsyncSendfile };
+#[cfg(target_os = "linux")]
+pub use crate::ktls::KernelSocket;
+
+/// What is known about the other end of a connection.
+#[derive(Clone, Default)]
+pub struct Peer {
+    /// the client address, taken from the PROXY header if there was one.
+    pub addr: Option<SocketAddr>,
+
+    pub cert: Option<Arc<ClientCert>>,
+
+    /// the TLS server name indication, lowercase.
+    pub server_name: Option<Arc<str>>,
+
+    /// whether the connection is TLS, or the client's was as a trusted proxy tells us.
+    pub secure: bool,
+
+    /// the path prefix a trusted proxy strips from requests.
+    pub prefix: Option<Arc<str>>,
+
+    /// bandwidth left to this connection, if it is limited.
+    pub bucket: Option<Arc<Bucket>>,
+
+    /// bandwidth left to this connection below each limited path.
+    pub path_buckets: Arc<PathBuckets>,
+
+    /// files waiting to be sent with `sendfile(2)`, on plaintext or kernel TLS connections.
+    pub sendfile: Option<Arc<Sendfile>>,
+
+    /// the responses the connection's idle timeout waits for.
+    pub in_flight: Option<Arc<InFlight>>
+}
 
 #[derive(Clone)]
 pub struct WebDir {
     pub root: Arc<Path>,
     pub index: bool,
+    pub symlink: SymlinkPolicy,
+    pub filter: Arc<Filter>,
+    pub auth: Arc<Auth>,
+
+    /// serve `.br`, `.zst` or `.gz` siblings of files to clients that accept them.
+    pub precompressed: bool,
+
+    /// compress other bodies as they are sent, if set.
+    pub compression: Option<Arc<Compression>>,
+
+    /// send file bodies on plaintext HTTP/1 connections with `sendfile(2)`, Linux only.
+    pub zero_copy: bool,
+
+    /// hand TLS records to the kernel after the handshake, so HTTPS can use `sendfile(2)` too, Linux only.
+    pub ktls: bool,
+
+    /// virtual hosts by lowercase name, `self` is the default host.
+    pub hosts: Arc<HashMap<String, WebDir>>,
+
+    /// answer unknown hosts with 404 instead of the default host.
+    pub strict_hosts: bool,
+
+    /// pending ACME HTTP-01 challenges, answered for every host.
+    pub acme: Option<Arc<Challenges>>,
+
+    /// `Strict-Transport-Security` value sent on TLS connections.
+    pub hsts: Option<HeaderValue>,
+
+    /// bodies being sent, should be shared by all hosts.
+    pub transfers: Arc<Transfers>,
+
+    /// how long to wait on slow clients, and how often we gave up.
+    pub timeouts: Arc<Timeouts>,
+
+    /// caps on concurrent connections, shared by all listeners.
+    pub limits: Arc<Limits>,
+
+    /// bandwidth limits for file bodies, shared by all hosts.
+    pub throttle: Arc<Throttle>,
+
+    /// peers allowed to tell us the client address, scheme and path prefix.
+    pub trusted_proxies: Arc<TrustedProxies>,
+
+    /// where the root is mounted, such as `/files`, without a trailing `/`.
+    pub base_path: Arc<str>,
+    pub peer: Peer,
 }
 
 impl WebDir {
     pub fn new(root: Arc<Path>, index: bool) -> io::Result<Self> {
-        Ok(WebDir { root, index })
+        let root: Arc<Path> = Arc::from(root.canonicalize()?);
+        let filter = Arc::new(Filter::new(&root, true, &[])?);
+        Ok(WebDir {
+            root, index, filter,
+            symlink: SymlinkPolicy::default(),
+            auth: Arc::new(Auth::default()),
+            precompressed: false,
+            compression: None,
+            zero_copy: cfg!(target_os = "linux"),
+            ktls: false,
+            hosts: Arc::new(HashMap::new()),
+            strict_hosts: false,
+            acme: None,
+            hsts: None,
+            transfers: Arc::new(Transfers::default()),
+            timeouts: Arc::new(Timeouts::default()),
+            limits: Arc::new(Limits::default()),
+            throttle: Arc::new(Throttle::default()),
+            trusted_proxies: Arc::new(TrustedProxies::default()),
+            base_path: Arc::from(""),
+            peer: Peer::default()
+        })
+    }
+
+    /// Take the settings every host shares from `default`, for a virtual host.
+    pub fn inherit(mut self, default: &WebDir) -> Self {
+        self.precompressed = default.precompressed;
+        self.compression = default.compression.clone();
+        self.zero_copy = default.zero_copy;
+        self.ktls = default.ktls;
+        self.transfers = default.transfers.clone();
+        self.timeouts = default.timeouts.clone();
+        self.limits = default.limits.clone();
+        self.throttle = default.throttle.clone();
+        self.trusted_proxies = default.trusted_proxies.clone();
+        self.base_path = default.base_path.clone();
+        self
+    }
+
+    /// The exact name first, then a wildcard for its parent, as SNI does.
+    fn host(&self, name: Option<&str>) -> Option<&WebDir> {
+        let host = name.and_then(|name| {
+            self.hosts.get(name).or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                self.hosts.get(&format!("*.{}", parent))
+            })
+        });
+
+        match host {
+            Some(host) => Some(host),
+            None if self.strict_hosts => None,
+            None => Some(self)
+        }
+    }
+
+    /// The peer, with what a trusted proxy tells us about the client behind it.
+    fn forwarded(&self, req: &Request<Incoming>) -> Peer {
+        let mut peer = self.peer.clone();
+
+        if self.trusted_proxies.is_empty()
+            || !self.trusted_proxies.contains(peer.addr.map(|addr| addr.ip()))
+        {
+            return peer;
+        }
+
+        let forwarded = Forwarded::from_headers(req.headers(), &self.trusted_proxies);
+        debug!(?forwarded, "request/forwarded");
+
+        if let Some(client) = forwarded.client {
+            peer.addr = Some(client);
+        }
+        if let Some(secure) = forwarded.secure {
+            peer.secure = secure;
+        }
+        peer.prefix = forwarded.prefix.map(Into::into);
+        peer
+    }
+
+    fn route(&self, peer: &Peer, req: Request<Incoming>) -> Response<Body> {
+        if let Some(resp) = self.acme.as_deref().and_then(|acme| acme_response(acme, &req)) {
+            return resp;
+        }
+
+        let name = request_host(&req);
+        let host = match self.host(name.as_deref()) {
+            Some(host) => host,
+            None => {
+                info!(host=?name, "request/unknown host");
+                return err_response(StatusCode::NOT_FOUND, format_args!("Unknown host"));
+            }
+        };
+
+        // the connection's certificate was chosen for another host
+        if let Some(server_name) = peer.server_name.as_deref() {
+            if !self.host(Some(server_name)).is_some_and(|sni_host| ptr::eq(sni_host, host)) {
+                info!(host=?name, %server_name, "request/misdirected");
+                return err_response(StatusCode::MISDIRECTED_REQUEST, format_args!("Misdirected request"));
+            }
+        }
+
+        host.serve(peer, req)
+    }
+
+    fn serve(&self, peer: &Peer, req: Request<Incoming>) -> Response<Body> {
+        let span = Span::current();
+
+        let cert = peer.cert.as_deref();
+        if let Some(cert) = cert {
+            span.record("cert", cert.subject.as_str());
+        }
+
+        // outside the base path there is nothing of ours
+        let path = match strip_base(&self.base_path, req.uri().path()) {
+            Some(path) => path.to_owned(),
+            None => return err_response(StatusCode::NOT_FOUND, format_args!("Not found"))
+        };
+
+        // a symlink may lead somewhere rules protect that its own path doesn't
+        let decoded = decode_path(&path);
+        let resolved = resolve_path(&self.root, self.symlink, &decoded);
+        let access = self.auth.check(&decoded, &resolved, req.headers(), cert);
+        if let Access::User(user) = &access {
+            span.record("user", user.as_str());
+        }
+
+        info!("request");
+        debug!(headers=?req.headers(), "request headers");
+
+        match access {
+            Access::Public | Access::User(_) => (),
+            Access::Forbidden => {
+                debug!("auth/forbidden");
+                return err_response(StatusCode::FORBIDDEN, format_args!("Forbidden"));
+            },
+            Access::Denied(realm) => {
+                debug!(realm=%realm.name, "auth/denied");
+
+                let mut resp = err_response(StatusCode::UNAUTHORIZED, format_args!("Unauthorized"));
+                resp.headers_mut().insert(WWW_AUTHENTICATE, realm.challenge());
+                return resp;
+            }
+        }
+
+        let base = format!("{}{}", peer.prefix.as_deref().unwrap_or_default(), self.base_path);
+
+        let buckets = self.throttle.buckets(&decode_path(&path), peer.bucket.as_ref(), &peer.path_buckets);
+
+        // HTTP/2 frames its data, so only HTTP/1 can hand the socket a file
+        let sendfile = peer.sendfile.clone().filter(|_| req.version() <= Version::HTTP_11);
+
+        match Process::new(self, req, path, base).throttle(buckets).sendfile(sendfile).process() {
+            Ok(resp) => resp,
+            Err(err) => {
+                let status = match err.kind() {
+                    io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+                    io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+                    _ => StatusCode::INTERNAL_SERVER_ERROR
+                };
+
+                err_response(status, format_args!("{:?}", err))
+            }
+        }
     }
 }
 
@@ -40,23 +323,241 @@ impl Service<Request<Incoming>> for WebDir {
     type Future = future::Ready<Result<Response<Body>, Self::Error>>;
 
     fn call(&self, req: Request<Incoming>) -> Self::Future {
-        info!(method=%req.method(), path=%req.uri().path(), "request");
-        debug!(headers=?req.headers(), "request headers");
+        let span = info_span!("request",
+            method=%req.method(), path=%req.uri().path(),
+            peer=field::Empty, user=field::Empty, cert=field::Empty
+        );
+        let _enter = span.enter();
 
-        match Process::new(self, req).process() {
-            Ok(resp) => future::ok(resp),
-            Err(err) => {
-                let body = err_html(format_args!("{:?}", err)).into_string();
-                let mut resp = Response::new(Body::one(body.into()));
+        let peer = self.forwarded(&req);
+        if let Some(addr) = peer.addr {
+            span.record("peer", field::display(addr));
+        }
+
+        let responding = peer.in_flight.as_ref().map(InFlight::start);
+        let mut resp = self.route(&peer, req)
+            .map(|body| body.responding(responding));
+
+        if peer.secure {
+            if let Some(hsts) = self.hsts.as_ref() {
+                resp.headers_mut().insert(STRICT_TRANSPORT_SECURITY, hsts.clone());
+            }
+        }
+
+        future::ok(resp)
+    }
+}
+
+/// Answer a pending ACME HTTP-01 challenge, if `req` is one.
+fn acme_response<B>(acme: &Challenges, req: &Request<B>) -> Option<Response<Body>> {
+    let token = req.uri().path().strip_prefix("/.well-known/acme-challenge/")?;
+    let key_auth = acme.http(token)?;
+
+    info!("request/acme challenge");
+    let mut resp = Response::new(Body::one(key_auth.into()));
+    resp.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/octet-stream"));
+    Some(resp)
+}
+
+/// The rest of `path` below `base`, `None` if it isn't there.
+fn strip_base<'a>(base: &str, path: &'a str) -> Option<&'a str> {
+    match path.strip_prefix(base)? {
+        rest if rest.is_empty() || rest.starts_with('/') => Some(rest),
+        _ => None
+    }
+}
+
+fn request_host<B>(req: &Request<B>) -> Option<String> {
+    let host = match req.uri().host() {
+        Some(host) => host.to_owned(),
+        None => req.headers()
+            .get(HOST)?
+            .to_str()
+            .ok()?
+            .parse::<Authority>()
+            .ok()?
+            .host()
+            .to_owned()
+    };
+
+    Some(host.trim_end_matches('.').to_ascii_lowercase())
+}
+
+fn err_response(status: StatusCode, display: fmt::Arguments) -> Response<Body> {
+    let body = err_html(display).into_string();
+    let mut resp = Response::new(Body::one(body.into()));
+    *resp.status_mut() = status;
+    resp
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{ env, fs, process };
+    use std::path::PathBuf;
+    use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+    use tokio::net::{ TcpListener, TcpStream };
+    use super::*;
+
+    /// An empty directory of our own below the temporary one.
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("webdir-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Serve `webdir` on a loopback port, with `sendfile(2)` if it is on.
+    pub(crate) async fn serve(webdir: WebDir) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
 
-                match err.kind() {
-                    io::ErrorKind::NotFound => *resp.status_mut() =  StatusCode::NOT_FOUND,
-                    io::ErrorKind::PermissionDenied => *resp.status_mut() =  StatusCode::FORBIDDEN,
-                    _ => *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR
+        tokio::spawn(async move {
+            loop {
+                let (tcp, addr) = listener.accept().await.unwrap();
+                let mut webdir = webdir.clone();
+                webdir.peer.addr = Some(addr);
+                if webdir.zero_copy {
+                    webdir.peer.sendfile = Some(Arc::new(Sendfile::default()));
                 }
 
-                future::ok(resp)
+                let stream = WebStream::new(Socket::Tcp(tcp), None, false).await.unwrap();
+                let stream = SendfileStream::new(stream, webdir.peer.sendfile.clone());
+                let conn = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(hyper_util::rt::tokio::TokioIo::new(stream), webdir);
+                tokio::spawn(conn);
             }
+        });
+
+        addr
+    }
+
+    /// `GET path` with extra `headers`, the response head and its body, unchunked.
+    pub(crate) async fn get(addr: SocketAddr, path: &str, headers: &[&str]) -> (String, Vec<u8>) {
+        let mut req = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n", path);
+        for header in headers {
+            req.push_str(header);
+            req.push_str("\r\n");
+        }
+        req.push_str("\r\n");
+
+        let mut tcp = TcpStream::connect(addr).await.unwrap();
+        tcp.write_all(req.as_bytes()).await.unwrap();
+        let mut buf = Vec::new();
+        tcp.read_to_end(&mut buf).await.unwrap();
+
+        let end = buf.windows(4).position(|window| window == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(buf[..end].to_vec()).unwrap().to_ascii_lowercase();
+        let mut body = buf[end + 4..].to_vec();
+
+        if head.contains("transfer-encoding: chunked") {
+            let mut rest = &body[..];
+            let mut unchunked = Vec::new();
+            loop {
+                let line = rest.windows(2).position(|window| window == b"\r\n").unwrap();
+                let len = usize::from_str_radix(std::str::from_utf8(&rest[..line]).unwrap(), 16).unwrap();
+                if len == 0 {
+                    break;
+                }
+                unchunked.extend_from_slice(&rest[line + 2..][..len]);
+                rest = &rest[line + 2 + len + 2..];
+            }
+            body = unchunked;
         }
+
+        (head, body)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn compressed_twice_from_cache() {
+        let dir = temp_dir("compress-twice");
+        let (root, cache) = (dir.join("root"), dir.join("cache"));
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(&cache).unwrap();
+        fs::write(root.join("a.txt"), "compress me ".repeat(1000)).unwrap();
+
+        let mut webdir = WebDir::new(Arc::from(root.as_path()), false).unwrap();
+        let mut compression = Compression::default();
+        compression.max_size = u64::MAX;
+        compression.cache = Some(cache.clone());
+        compression.cache_max = u64::MAX;
+        webdir.compression = Some(Arc::new(compression));
+        let addr = serve(webdir).await;
+
+        let (head, first) = get(addr, "/a.txt", &["Accept-Encoding: gzip"]).await;
+        assert!(head.starts_with("http/1.1 200"), "{}", head);
+        assert!(head.contains("content-encoding: gzip"), "{}", head);
+        assert_eq!(fs::read_dir(&cache).unwrap().count(), 1);
+
+        // the cache lies outside the root, but it is ours
+        let (head, second) = get(addr, "/a.txt", &["Accept-Encoding: gzip"]).await;
+        assert!(head.starts_with("http/1.1 200"), "{}", head);
+        assert!(head.contains(&format!("content-length: {}", first.len())), "{}", head);
+        assert_eq!(first, second);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn redirect_dir_stays_on_site() {
+        let root = temp_dir("redirect-dir");
+        fs::create_dir_all(root.join("evil.com")).unwrap();
+        fs::create_dir_all(root.join("a b")).unwrap();
+
+        let webdir = WebDir::new(Arc::from(root.as_path()), false).unwrap();
+        let addr = serve(webdir).await;
+
+        let (head, _) = get(addr, "//evil.com", &[]).await;
+        assert!(head.starts_with("http/1.1 301"), "{}", head);
+        assert!(head.contains("\r\nlocation: /evil%2ecom/\r\n"), "{}", head);
+
+        let (head, _) = get(addr, "/a%20b?x=1", &[]).await;
+        assert!(head.contains("\r\nlocation: /a%20b/?x=1"), "{}", head);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn vhost_inherits_shared_settings() {
+        let root = temp_dir("vhost-inherit");
+        let mut webdir = WebDir::new(Arc::from(root.as_path()), false).unwrap();
+        webdir.precompressed = true;
+        webdir.compression = Some(Arc::new(Compression::default()));
+        webdir.zero_copy = false;
+        webdir.ktls = true;
+        webdir.limits = Arc::new(Limits::new(Some(1), None));
+        webdir.trusted_proxies = Arc::new(TrustedProxies(vec!["127.0.0.1".parse().unwrap()]));
+        webdir.base_path = Arc::from("/files");
+
+        let host = WebDir::new(Arc::from(root.as_path()), false).unwrap().inherit(&webdir);
+        assert!(host.precompressed);
+        assert!(Arc::ptr_eq(host.compression.as_ref().unwrap(), webdir.compression.as_ref().unwrap()));
+        assert!(!host.zero_copy);
+        assert!(host.ktls);
+        assert!(Arc::ptr_eq(&host.transfers, &webdir.transfers));
+        assert!(Arc::ptr_eq(&host.timeouts, &webdir.timeouts));
+        assert!(Arc::ptr_eq(&host.limits, &webdir.limits));
+        assert!(Arc::ptr_eq(&host.throttle, &webdir.throttle));
+        assert!(Arc::ptr_eq(&host.trusted_proxies, &webdir.trusted_proxies));
+        assert_eq!(&*host.base_path, "/files");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn forwarded_only_from_trusted_proxies() {
+        let root = temp_dir("forwarded");
+        fs::create_dir_all(root.join("dir")).unwrap();
+        let headers = ["X-Forwarded-Prefix: /outer", "X-Forwarded-Proto: https"];
+
+        let mut webdir = WebDir::new(Arc::from(root.as_path()), false).unwrap();
+        webdir.trusted_proxies = Arc::new(TrustedProxies(vec!["10.0.0.0/8".parse().unwrap()]));
+        let (head, _) = get(serve(webdir.clone()).await, "/dir", &headers).await;
+        assert!(head.contains("\r\nlocation: /dir/\r\n"), "{}", head);
+
+        webdir.trusted_proxies = Arc::new(TrustedProxies(vec!["127.0.0.1".parse().unwrap()]));
+        let (head, _) = get(serve(webdir).await, "/dir", &headers).await;
+        assert!(head.contains("\r\nlocation: /outer/dir/\r\n"), "{}", head);
+
+        fs::remove_dir_all(&root).unwrap();
     }
 }
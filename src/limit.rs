@@ -0,0 +1,69 @@
+use std::net::IpAddr;
+use std::sync::{ Arc, Mutex };
+use std::collections::HashMap;
+use tokio::sync::{ Semaphore, OwnedSemaphorePermit };
+
+
+/// Caps on concurrent connections, overall and per client address.
+#[derive(Default)]
+pub struct Limits {
+    global: Option<Arc<Semaphore>>,
+    per_ip: Option<usize>,
+    by_ip: Mutex<HashMap<IpAddr, usize>>
+}
+
+/// A connection counted against its client address, until dropped.
+pub struct IpPermit {
+    limits: Arc<Limits>,
+    ip: IpAddr
+}
+
+impl Limits {
+    pub fn new(global: Option<usize>, per_ip: Option<usize>) -> Limits {
+        Limits {
+            global: global.map(|max| Arc::new(Semaphore::new(max))),
+            per_ip,
+            by_ip: Mutex::new(HashMap::new())
+        }
+    }
+
+    /// Wait for room under the global cap, so excess connections queue in the listen backlog.
+    pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        let global = self.global.as_ref()?;
+
+        match global.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                info!("limit/connections full");
+                global.clone().acquire_owned().await.ok()
+            }
+        }
+    }
+
+    /// `None` if `ip` already has as many connections as allowed.
+    pub fn acquire_ip(self: &Arc<Self>, ip: IpAddr) -> Option<IpPermit> {
+        let ip = ip.to_canonical();
+        let mut by_ip = self.by_ip.lock().unwrap();
+        let count = by_ip.entry(ip).or_insert(0);
+
+        if self.per_ip.is_some_and(|max| *count >= max) {
+            return None;
+        }
+
+        *count += 1;
+        Some(IpPermit { limits: self.clone(), ip })
+    }
+}
+
+impl Drop for IpPermit {
+    fn drop(&mut self) {
+        let mut by_ip = self.limits.by_ip.lock().unwrap();
+
+        if let Some(count) = by_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                by_ip.remove(&self.ip);
+            }
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::{ fmt, io };
+use std::pin::Pin;
+use std::str::FromStr;
+use std::net::SocketAddr;
+use std::path::{ Path, PathBuf };
+use std::task::{ Context, Poll };
+use std::io::IoSlice;
+use tokio::io::{ AsyncRead, AsyncWrite, ReadBuf };
+use tokio::net::{ TcpListener, TcpStream };
+use crate::sendfile::AsyncSendfile;
+#[cfg(unix)]
+use std::mem;
+#[cfg(unix)]
+use std::os::unix::io::{ AsRawFd, OwnedFd, RawFd };
+#[cfg(unix)]
+use tokio::net::{ UnixListener, UnixStream };
+
+
+/// Where a listener accepts connections, `unix:<path>` for a Unix domain socket.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Addr {
+    Tcp(SocketAddr),
+    Unix(PathBuf)
+}
+
+impl FromStr for Addr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(Addr::Unix(path.into())),
+            None => s.parse()
+                .map(Addr::Tcp)
+                .map_err(|err| format!("bad address {}: {}", s, err))
+        }
+    }
+}
+
+impl fmt::Display for Addr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Addr::Tcp(addr) => addr.fmt(f),
+            Addr::Unix(path) => write!(f, "unix:{}", path.display())
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener)
+}
+
+impl Listener {
+    /// A stale Unix socket left at the path is replaced.
+    pub async fn bind(addr: &Addr) -> io::Result<Listener> {
+        match addr {
+            Addr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            #[cfg(unix)]
+            Addr::Unix(path) => Listener::bind_unix(path, None),
+            #[cfg(not(unix))]
+            Addr::Unix(_) => Err(io::Error::new(io::ErrorKind::Unsupported, "unix sockets are not supported"))
+        }
+    }
+
+    /// Replaces a socket left at `path` only if nobody serves it anymore.
+    ///
+    /// `mode` is applied through the umask, so the socket never exists with looser permissions.
+    #[cfg(unix)]
+    pub fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<Listener> {
+        use std::os::unix::fs::FileTypeExt;
+
+        if path.symlink_metadata().is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is served by another process", path.display())
+                )),
+                Err(err) if err.raw_os_error() == Some(libc::ECONNREFUSED) => std::fs::remove_file(path)?,
+                Err(_) => ()
+            }
+        }
+
+        let listener = match mode {
+            // the umask is per process, but nothing else creates files while we start
+            Some(mode) => {
+                let old = unsafe { libc::umask(!mode as libc::mode_t & 0o777) };
+                let listener = UnixListener::bind(path);
+                unsafe { libc::umask(old) };
+                listener?
+            },
+            None => UnixListener::bind(path)?
+        };
+
+        Ok(Listener::Unix(listener))
+    }
+
+    /// Take over a listening socket, such as one passed by the service manager.
+    #[cfg(unix)]
+    pub fn from_fd(fd: OwnedFd) -> io::Result<Listener> {
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of_val(&addr) as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockname(fd.as_raw_fd(), &mut addr as *mut _ as *mut libc::sockaddr, &mut len)
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let ret = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        match libc::c_int::from(addr.ss_family) {
+            libc::AF_INET | libc::AF_INET6 => {
+                let listener = std::net::TcpListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+            },
+            libc::AF_UNIX => {
+                let listener = std::os::unix::net::UnixListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Ok(Listener::Unix(UnixListener::from_std(listener)?))
+            },
+            family => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported socket family: {}", family)
+            ))
+        }
+    }
+
+    /// The peer address is `None` for Unix sockets.
+    pub async fn accept(&self) -> io::Result<(Socket, Option<SocketAddr>)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (socket, addr) = listener.accept().await?;
+                Ok((Socket::Tcp(socket), Some(addr)))
+            },
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (socket, _) = listener.accept().await?;
+                Ok((Socket::Unix(socket), None))
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<Addr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(Addr::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let addr = listener.local_addr()?;
+                Ok(Addr::Unix(addr.as_pathname().map(Into::into).unwrap_or_default()))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd()
+        }
+    }
+}
+
+/// An accepted connection.
+pub enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream)
+}
+
+macro_rules! socket {
+    ( $self:expr, $io:ident => $e:expr ) => {
+        match $self {
+            Socket::Tcp($io) => $e,
+            #[cfg(unix)]
+            Socket::Unix($io) => $e
+        }
+    }
+}
+
+impl AsyncRead for Socket {
+    #[inline]
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        socket!(self.get_mut(), io => Pin::new(io).poll_read(cx, buf))
+    }
+}
+
+impl AsyncWrite for Socket {
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        socket!(self.get_mut(), io => Pin::new(io).poll_write(cx, buf))
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        socket!(self.get_mut(), io => Pin::new(io).poll_flush(cx))
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        socket!(self.get_mut(), io => Pin::new(io).poll_shutdown(cx))
+    }
+
+    #[inline]
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>])
+        -> Poll<io::Result<usize>>
+    {
+        socket!(self.get_mut(), io => Pin::new(io).poll_write_vectored(cx, bufs))
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        socket!(self, io => io.is_write_vectored())
+    }
+}
+
+impl AsyncSendfile for Socket {
+    #[inline]
+    fn poll_sendfile(self: Pin<&mut Self>, cx: &mut Context<'_>, file: &std::fs::File, offset: u64, len: usize)
+        -> Poll<io::Result<usize>>
+    {
+        socket!(self.get_mut(), io => Pin::new(io).poll_sendfile(cx, file, offset, len))
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl crate::ktls::KernelSocket for Socket {
+    /// Unix domain sockets have no TLS ULP.
+    #[inline]
+    fn kernel_fd(&self) -> Option<RawFd> {
+        match self {
+            Socket::Tcp(io) => Some(io.as_raw_fd()),
+            Socket::Unix(_) => None
+        }
+    }
+}
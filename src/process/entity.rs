@@ -3,22 +3,32 @@ use std::ops::{ Bound, Range };
 use std::path::Path;
 use std::fs::Metadata;
 use std::str::FromStr;
-use std::cell::RefCell;
 use smallvec::SmallVec;
 use rand::{ Rng, thread_rng, distributions::Alphanumeric };
 use bytes::Bytes;
 use hyper::StatusCode;
 use http::HeaderMap;
+use http::header::CONTENT_ENCODING;
 use headers::HeaderMapExt;
 use mime::Mime;
 use data_encoding::BASE64URL_NOPAD;
-use crate::utils::{ err_html, fs_hash };
+use crate::utils::{ err_html, fs_hash, Kind };
+use crate::encoding::Encoding;
 
 
 pub struct Entity<'a> {
     pub path: &'a Path,
     pub length: u64,
+    pub kind: Kind,
+    pub mime: Mime,
+
+    /// set when `path` is a precompressed sibling of the requested file.
+    pub encoding: Option<Encoding>,
+
+    /// set when `path` is our own cached copy, outside the root.
+    pub cached: bool,
     metadata: &'a Metadata,
+    tag: String,
     etag: headers::ETag
 }
 
@@ -33,35 +43,58 @@ pub enum Value {
 
 impl<'a> Entity<'a> {
     pub fn new(path: &'a Path, metadata: &'a Metadata) -> Self {
-        thread_local!{
-            static BUF: RefCell<String> = RefCell::new(String::with_capacity(16));
-        }
+        let tag = tag(metadata, None);
 
-        let hash = fs_hash(metadata);
+        Entity {
+            path, metadata,
+            etag: etag(&tag),
+            tag,
+            length: metadata.len(),
+            kind: Kind::of(metadata),
+            mime: mime_guess::from_path(path).first_or_octet_stream(),
+            encoding: None,
+            cached: false
+        }
+    }
 
-        let etag = BUF.with(|buf| {
-            let mut buf = buf.borrow_mut();
+    /// Serve `path` as `original` in `encoding`, with an ETag of its own.
+    pub fn encoded(mut self, original: &Path, encoding: Encoding) -> Self {
+        self.tag = tag(self.metadata, Some(encoding));
+        self.etag = etag(&self.tag);
+        self.mime = mime_guess::from_path(original).first_or_octet_stream();
+        self.encoding = Some(encoding);
+        self
+    }
 
-            buf.clear();
-            buf.push('"');
-            BASE64URL_NOPAD.encode_append(&hash.to_le_bytes(), &mut buf);
-            buf.push('"');
+    /// Serve the file compressed on the fly, with an ETag of its own.
+    pub fn compressed(mut self, encoding: Encoding) -> Self {
+        self.tag = tag(self.metadata, Some(encoding));
+        self.etag = etag(&self.tag);
+        self.encoding = Some(encoding);
+        self
+    }
 
-            buf.parse().unwrap()
-        });
+    /// Read the compressed file from the cache instead.
+    pub fn cached(mut self, path: &'a Path, length: u64) -> Self {
+        self.path = path;
+        self.length = length;
+        self.cached = true;
+        self
+    }
 
-        Entity {
-            path, metadata, etag,
-            length: metadata.len()
-        }
+    /// The opaque part of the ETag.
+    pub fn tag(&self) -> &str {
+        &self.tag
     }
 
     pub fn headers(&self) -> HeaderMap {
         let mut map = HeaderMap::new();
 
         map.typed_insert(headers::AcceptRanges::bytes());
-        let mime = mime_guess::from_path(self.path).first_or_octet_stream();
-        map.typed_insert(headers::ContentType::from(mime));
+        map.typed_insert(headers::ContentType::from(self.mime.clone()));
+        if let Some(encoding) = self.encoding {
+            map.insert(CONTENT_ENCODING, encoding.header());
+        }
 
         map.typed_insert(self.etag.clone());
 
@@ -89,6 +122,14 @@ impl<'a> Entity<'a> {
     }
 
     pub fn result(&self, map: &HeaderMap) -> Result {
+        if self.kind != Kind::File {
+            return Result(
+                StatusCode::FORBIDDEN,
+                HeaderMap::new(),
+                Value::Error(Bytes::from("Not a regular file"))
+            );
+        }
+
         if let Some(ifmatch) = map.typed_get::<headers::IfMatch>() {
             if !ifmatch.precondition_passes(&self.etag) {
                 return Result(
@@ -114,7 +155,7 @@ impl<'a> Entity<'a> {
 
         if let Some(ifnonematch) = map.typed_get::<headers::IfNoneMatch>() {
             if !ifnonematch.precondition_passes(&self.etag) {
-                return not_modified(format_args!("etag: {:?}", &self.etag));
+                return not_modified(format_args!("etag: {:?}", self.etag));
             }
         }
 
@@ -184,6 +225,19 @@ impl<'a> Entity<'a> {
 }
 
 
+fn tag(metadata: &Metadata, encoding: Option<Encoding>) -> String {
+    let mut tag = BASE64URL_NOPAD.encode(&fs_hash(metadata).to_le_bytes());
+    if let Some(encoding) = encoding {
+        tag.push('-');
+        tag.push_str(encoding.name());
+    }
+    tag
+}
+
+fn etag(tag: &str) -> headers::ETag {
+    format!("\"{}\"", tag).parse().unwrap()
+}
+
 pub fn not_modified(dis: fmt::Arguments) -> Result {
     debug!(msg=%dis, "send/cache");
 
@@ -2,59 +2,131 @@ mod entity;
 mod sortdir;
 
 use std::io;
+use std::sync::Arc;
 use std::ops::Range;
-use std::path::PathBuf;
-use std::fs::{ Metadata, ReadDir };
+use std::path::{ Path, PathBuf };
+use std::fs::{ self, Metadata, ReadDir };
+#[cfg(unix)]
+use std::os::unix::io::AsFd;
 use futures::future::TryFutureExt;
 use bytes::Bytes;
 use hyper::{ Request, Response, Method, StatusCode };
 use hyper::body::Incoming;
-use http::HeaderMap;
+use http::{ HeaderMap, HeaderValue };
+use http::header::{ LOCATION, VARY, RANGE, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH };
 use headers::HeaderMapExt;
 use if_chain::if_chain;
+use tokio::task::block_in_place;
+use tracing::Instrument;
 use maud::Render;
 use crate::WebDir;
 use crate::file::File;
+use crate::filter::DirFilter;
 use crate::body::ResponseBody as Body;
-use crate::utils::{ path_canonicalize, decode_path, html_utf8, LimitFile };
+use crate::throttle::Bucket;
+use crate::sendfile::Sendfile;
+use crate::encoding::{ self, Encoding, Encoder };
+use crate::utils::{ path_canonicalize, decode_path, encode_dir_url, html_utf8, Kind, LimitFile };
 use self::entity::Entity;
 use self::sortdir::{ up, SortDir };
 
 
 pub struct Process<'a> {
     webdir: &'a WebDir,
-    req: Request<Incoming>
+    req: Request<Incoming>,
+
+    /// the request path below the base path, still percent-encoded.
+    path: String,
+
+    /// the prefix for generated URLs, as the client sees it.
+    base: String,
+
+    /// what file bodies are paced by.
+    buckets: Vec<Arc<Bucket>>,
+
+    /// where to queue files the connection can send itself.
+    sendfile: Option<Arc<Sendfile>>
 }
 
 impl<'a> Process<'a> {
-    pub fn new(webdir: &'a WebDir, req: Request<Incoming>) -> Process<'a> {
-        Process { webdir, req }
+    pub fn new(webdir: &'a WebDir, req: Request<Incoming>, path: String, base: String) -> Process<'a> {
+        Process { webdir, req, path, base, buckets: Vec::new(), sendfile: None }
+    }
+
+    pub fn throttle(mut self, buckets: Vec<Arc<Bucket>>) -> Process<'a> {
+        self.buckets = buckets;
+        self
+    }
+
+    pub fn sendfile(mut self, slot: Option<Arc<Sendfile>>) -> Process<'a> {
+        self.sendfile = slot;
+        self
     }
 
     pub fn process(self) -> io::Result<Response<Body>> {
-        let path = decode_path(self.req.uri().path());
-        let (depth, target) =
-            path_canonicalize(&self.webdir.root, &path);
+        let path = decode_path(&self.path);
+        let (_, target) = path_canonicalize(&self.webdir.root, &path);
+        self.webdir.symlink.check(&self.webdir.root, &target)?;
         let metadata = target.metadata()?;
 
-        Ok(if let Ok(dir) = target.read_dir() {
-            if_chain!{
-                if self.webdir.index;
-                if let index_path = target.join("index.html");
-                if let Ok(try_index) = index_path.metadata();
-                if try_index.is_file();
-                then {
-                    self.process_file(index_path, try_index)
-                } else {
-                    self.process_dir(dir, depth == 0)
+        if self.webdir.filter.is_ignored(&self.webdir.root, &target, metadata.is_dir()) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "ignored"));
+        }
+
+        Ok(match Kind::of(&metadata) {
+            // relative links in the listing only work below the trailing `/`
+            Kind::Dir if !self.path.ends_with('/') => self.redirect_dir(&target),
+            Kind::Dir => {
+                let dir = target.read_dir()?;
+
+                if_chain!{
+                    if self.webdir.index;
+                    if let index_path = target.join("index.html");
+                    if self.webdir.symlink.check(&self.webdir.root, &index_path).is_ok();
+                    if !self.webdir.filter.is_ignored(&self.webdir.root, &index_path, false);
+                    if let Ok(try_index) = index_path.metadata();
+                    if Kind::of(&try_index) == Kind::File;
+                    then {
+                        self.process_file(index_path, try_index)
+                    } else {
+                        let filter = DirFilter::new(self.webdir.filter.clone(), &self.webdir.root, &target);
+                        let parent = target.strip_prefix(&self.webdir.root).ok()
+                            .and_then(Path::parent)
+                            .map(|parent| encode_dir_url(&self.base, parent));
+                        self.process_dir(&target, dir, filter, parent)
+                    }
                 }
-            }
-        } else {
-            self.process_file(target, metadata)
+            },
+            Kind::File => self.process_file(target, metadata),
+            Kind::Special => return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "not a regular file"
+            ))
         })
     }
 
-    fn process_dir(self, dir: ReadDir, is_top: bool) -> Response<Body> {
+    fn redirect_dir(self, target: &Path) -> Response<Body> {
+        let relative = target.strip_prefix(&self.webdir.root).unwrap_or(Path::new(""));
+        let url = encode_dir_url(&self.base, relative);
+
+        // `//host/` would be another site to a browser
+        let mut location = format!("/{}", url.trim_start_matches('/'));
+        if let Some(query) = self.req.uri().query() {
+            location.push('?');
+            location.push_str(query);
+        }
+
+        debug!(%location, "send/redirect dir");
+
+        let mut resp = Response::new(Body::empty());
+        *resp.status_mut() = StatusCode::MOVED_PERMANENTLY;
+        if let Ok(location) = HeaderValue::from_str(&location) {
+            resp.headers_mut().insert(LOCATION, location);
+        }
+        resp
+    }
+
+    fn process_dir(self, path: &Path, dir: ReadDir, filter: DirFilter, parent: Option<String>) -> Response<Body> {
         const HTML_HEADER: &str = "<html><head><style>\
             .time { padding-left: 12em; }\
             .size {\
@@ -64,61 +136,127 @@ impl<'a> Process<'a> {
         </style></head><body><table><tbody>";
         const HTML_FOOTER: &str = "</tbody></table></body></html>";
 
-        let (mut sender, body) = Body::channel(None);
+        let encoding = self.webdir.compression.as_ref()
+            .and_then(|_| encoding::accepted(self.req.headers()).first().copied());
+        let (sender, body) = Body::channel(None);
+        let mut sender = sender
+            .timeout(self.webdir.timeouts.clone())
+            .compress(encoding.map(Encoder::new));
+        let policy = self.webdir.symlink;
+        let root = self.webdir.root.clone();
+        let body = body.track(self.webdir.transfers.start(path));
 
-        debug!(hint=%is_top, "send/dir");
+        debug!(?parent, "send/dir");
 
         let fut = async move {
             sender.send_data(Bytes::from_static(HTML_HEADER.as_bytes())).await?;
-            sender.send_data(Bytes::from(up(is_top).into_string().into_bytes())).await?;
-            for entry in SortDir::new(dir) {
+            sender.send_data(Bytes::from(up(parent.as_deref()).into_string().into_bytes())).await?;
+            let mut dir = SortDir::new(dir, policy, root, filter).await;
+            while let Some(entry) = dir.next_entry().await {
                 let string = entry?.render().into_string();
                 sender.send_data(Bytes::from(string.into_bytes())).await?;
             }
             sender.send_data(Bytes::from_static(HTML_FOOTER.as_bytes())).await?;
+            sender.finish().await?;
 
             Ok(()) as anyhow::Result<()>
         }.unwrap_or_else(|err| error!(?err, "send/dir"));
 
-        tokio::spawn(fut);
+        tokio::spawn(fut.in_current_span());
 
         let mut resp = Response::new(body);
         *resp.status_mut() = StatusCode::OK;
         resp.headers_mut()
             .typed_insert(html_utf8());
+        if self.webdir.compression.is_some() {
+            resp.headers_mut().insert(VARY, HeaderValue::from_static("accept-encoding"));
+        }
+        if let Some(encoding) = encoding {
+            resp.headers_mut().insert(CONTENT_ENCODING, encoding.header());
+        }
         resp
     }
 
     fn process_file(self, path: PathBuf, metadata: Metadata) -> Response<Body> {
-        let entity = Entity::new(&path, &metadata);
+        let cached;
+        let variant = match self.webdir.precompressed && Kind::of(&metadata) == Kind::File {
+            true => self.precompressed(&path),
+            false => None
+        };
+        let mut entity = match &variant {
+            Some((encoding, sibling, metadata)) => Entity::new(sibling, metadata).encoded(&path, *encoding),
+            None => Entity::new(&path, &metadata)
+        };
+
+        let compression = self.webdir.compression.as_deref()
+            .filter(|compression| variant.is_none()
+                && entity.kind == Kind::File
+                && compression.wants(&entity.mime, entity.length));
+        let mut compress = None;
+
+        if let Some(compression) = compression {
+            if let Some(&encoding) = encoding::accepted(self.req.headers()).first() {
+                entity = entity.compressed(encoding);
+                cached = compression.cached(entity.tag());
+
+                match &cached {
+                    Some((cached, metadata)) => entity = entity.cached(cached, metadata.len()),
+                    // ranges need the whole compressed body first
+                    None if self.req.headers().contains_key(RANGE) => entity = Entity::new(&path, &metadata),
+                    None => compress = Some(encoding)
+                }
+            }
+        }
 
         let entity::Result(status, mut map, value) = entity.result(self.req.headers());
+        if self.webdir.precompressed || compression.is_some() {
+            map.insert(VARY, HeaderValue::from_static("accept-encoding"));
+        }
+
         let mut resp = match value {
             entity::Value::Error(err) => {
                 map.typed_insert(html_utf8());
                 Response::new(Body::one(err))
             },
-            entity::Value::None => Response::new(self.sendchunk(&entity, None)),
-            entity::Value::Range(range) => Response::new(self.sendchunk(&entity, Some(range))),
+            entity::Value::None if compress.is_some() => {
+                // a resume would miss the cache and get the identity body, with another ETag
+                map.remove(CONTENT_LENGTH);
+                map.insert(ACCEPT_RANGES, HeaderValue::from_static("none"));
+                Response::new(self.sendchunk(&entity, None, compress))
+            },
+            entity::Value::None => Response::new(self.sendchunk(&entity, None, None)),
+            entity::Value::Range(range) => Response::new(self.sendchunk(&entity, Some(range), None)),
             entity::Value::Multipart(boundary, ranges) => {
                 if Method::HEAD == self.req.method() {
                     return Response::new(Body::empty());
                 }
 
-                let mime_type = mime_guess::from_path(entity.path).first_or_octet_stream();
+                let mime_type = entity.mime.clone();
+                let encoding = entity.encoding;
                 let boundary1 = format!("--{}\r\n", boundary);
                 let boundary2 = format!("--{}--", boundary);
 
                 let path = entity.path.to_owned();
+                let check = (!entity.cached).then(|| (self.webdir.root.clone(), self.webdir.symlink));
                 let length = entity.length;
-                let (mut sender, body) = Body::channel(None);
+                let (sender, body) = Body::channel(None);
+                let mut sender = sender
+                    .timeout(self.webdir.timeouts.clone())
+                    .throttle(self.buckets.clone());
+                let body = body.track(self.webdir.transfers.start(&path));
 
                 let fut = async move {
-                    let mut fd = File::open(&path).await?;
+                    let mut fd = match &check {
+                        Some((root, policy)) => File::open(&path, root, *policy).await?,
+                        None => File::open_trusted(&path).await?
+                    };
 
                     for range in ranges {
                         let mut map = HeaderMap::new();
                         map.typed_insert(headers::ContentType::from(mime_type.clone()));
+                        if let Some(encoding) = encoding {
+                            map.insert(CONTENT_ENCODING, encoding.header());
+                        }
                         map.typed_insert(headers::ContentRange::bytes(range.clone(), length).unwrap());
 
                         let mut headers = boundary1.as_bytes().to_vec();
@@ -149,7 +287,7 @@ impl<'a> Process<'a> {
                     Ok(()) as anyhow::Result<()>
                 }.unwrap_or_else(|err| error!(?err, "send/multipart"));
 
-                tokio::spawn(fut);
+                tokio::spawn(fut.in_current_span());
                 Response::new(body)
             }
         };
@@ -159,22 +297,79 @@ impl<'a> Process<'a> {
         resp
     }
 
-    pub fn sendchunk(&self, entity: &Entity, range: Option<Range<u64>>) -> Body {
+    /// The best sibling of `path` compressed in an encoding the client accepts.
+    fn precompressed(&self, path: &Path) -> Option<(Encoding, PathBuf, Metadata)> {
+        let webdir = self.webdir;
+
+        encoding::accepted(self.req.headers())
+            .into_iter()
+            .find_map(|encoding| {
+                let mut sibling = path.as_os_str().to_owned();
+                sibling.push(encoding.ext());
+                let sibling = PathBuf::from(sibling);
+
+                webdir.symlink.check(&webdir.root, &sibling).ok()?;
+                let metadata = sibling.metadata().ok()?;
+
+                if Kind::of(&metadata) != Kind::File || webdir.filter.is_ignored(&webdir.root, &sibling, false) {
+                    return None;
+                }
+
+                Some((encoding, sibling, metadata))
+            })
+    }
+
+    pub fn sendchunk(&self, entity: &Entity, range: Option<Range<u64>>, compress: Option<Encoding>) -> Body {
         if Method::HEAD == self.req.method() {
             return Body::empty();
         }
 
-        debug!(?range, "send/chunk");
+        debug!(?range, ?compress, "send/chunk");
 
         let path = entity.path.to_owned();
+        let check = (!entity.cached).then(|| (self.webdir.root.clone(), self.webdir.symlink));
         let range = range.unwrap_or(0..entity.length);
         let start = range.start;
         let len = range.end - range.start;
-        let (mut sender, body) = Body::channel(Some(len as u64));
+
+        // a paced body has to pass through our hands
+        if let (Some(slot), None, true) = (self.sendfile.as_ref(), compress, self.buckets.is_empty()) {
+            let open = || {
+                let file = fs::File::open(&path)?;
+                if let Some((root, policy)) = &check {
+                    #[cfg(unix)]
+                    policy.check_opened(root, &path, file.as_fd())?;
+                    #[cfg(not(unix))]
+                    policy.check(root, &path)?;
+                }
+                Ok(file) as io::Result<_>
+            };
+
+            match block_in_place(open) {
+                Ok(file) => return Body::sendfile(slot.clone(), file, start, len)
+                    .track(self.webdir.transfers.start(&path)),
+                Err(err) => error!(?err, "send/sendfile")
+            }
+        }
+
+        let encoder = compress.map(|encoding| {
+            let cache = self.webdir.compression.as_ref()
+                .and_then(|compression| compression.cache_file(entity.tag()));
+            Encoder::new(encoding).cache(cache)
+        });
+        let (sender, body) = Body::channel(if encoder.is_none() { Some(len) } else { None });
+        let mut sender = sender
+            .timeout(self.webdir.timeouts.clone())
+            .throttle(self.buckets.clone())
+            .compress(encoder);
+        let body = body.track(self.webdir.transfers.start(&path));
 
         let fut = async move {
             let mut fd = {
-                let mut fd = File::open(&path).await?;
+                let mut fd = match &check {
+                    Some((root, policy)) => File::open(&path, root, *policy).await?,
+                    None => File::open_trusted(&path).await?
+                };
                 fd.seek(io::SeekFrom::Start(start)).await?;
                 fd
             };
@@ -183,11 +378,12 @@ impl<'a> Process<'a> {
             while let Some(buf) = fd.next_chunk().await? {
                 sender.send_data(buf).await?;
             }
+            sender.finish().await?;
 
             Ok(()) as anyhow::Result<()>
         }.unwrap_or_else(|err| error!(?err, "send/chunk"));
 
-        tokio::spawn(fut);
+        tokio::spawn(fut.in_current_span());
         body
     }
 }
@@ -2,27 +2,87 @@ use std::{ io, fmt };
 use std::borrow::Cow;
 use std::cmp::Ordering;
 use std::ffi::OsString;
+use std::sync::Arc;
+use std::path::Path;
 use std::time::SystemTime;
-use std::fs::{ DirEntry, ReadDir, Metadata };
+use std::fs::{ DirEntry, ReadDir };
 use tokio::task::block_in_place;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use futures::{ future, stream, StreamExt };
 use smallvec::SmallVec;
 use maud::{ html, Render, Markup };
 use time::OffsetDateTime;
 use human_sort::compare;
-use crate::utils::encode_path;
+use crate::utils::{ encode_path, Kind };
+use crate::symlink::SymlinkPolicy;
+use crate::filter::DirFilter;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring;
 
 
 pub const SORTDIR_BUFF_LENGTH: usize = 1 << 12;
 
+/// How many entries past the sorted ones are loaded at a time.
+const BATCH_LENGTH: usize = 1 << 6;
+
 pub struct SortDir {
     readdir: ReadDir,
+    load: Load,
     buf: SmallVec<[io::Result<Entry>; 12]>
 }
 
+struct Load {
+    policy: SymlinkPolicy,
+    root: Arc<Path>,
+    filter: DirFilter
+}
+
+impl Load {
+    fn filter(&self, entry: io::Result<Entry>) -> Option<io::Result<Entry>> {
+        match entry {
+            Ok(entry) if self.filter.is_ignored(&entry.name, entry.kind == Kind::Dir) => None,
+            entry => Some(entry)
+        }
+    }
+
+    /// Up to `n` more entries, `None` once `readdir` is done.
+    async fn batch(&self, readdir: &mut ReadDir, n: usize) -> Option<SmallVec<[io::Result<Entry>; 12]>> {
+        // io_uring can't read directories, only stat what is in them
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(ring) = uring::ring() {
+            let entries = block_in_place(|| readdir.by_ref().take(n).collect::<Vec<_>>());
+            if entries.is_empty() {
+                return None;
+            }
+
+            let batch = stream::iter(entries)
+                .map(|entry| async move {
+                    Entry::statx(ring, entry?, self.policy, &self.root).await
+                })
+                .buffer_unordered(BATCH_LENGTH)
+                .filter_map(|entry| future::ready(self.filter(entry)))
+                .collect()
+                .await;
+            return Some(batch);
+        }
+
+        block_in_place(|| {
+            let mut count = 0;
+            let batch = readdir
+                .by_ref()
+                .take(n)
+                .inspect(|_| count += 1)
+                .filter_map(|entry| self.filter(entry.and_then(|entry| Entry::new(entry, self.policy, &self.root))))
+                .collect();
+            if count == 0 { None } else { Some(batch) }
+        })
+    }
+}
+
 impl SortDir {
-    pub fn new(mut readdir: ReadDir) -> Self {
+    pub async fn new(mut readdir: ReadDir, policy: SymlinkPolicy, root: Arc<Path>, filter: DirFilter) -> Self {
         fn sort_by_entry(x: &io::Result<Entry>, y: &io::Result<Entry>) -> Ordering {
-            if let (&Ok(ref x), &Ok(ref y)) = (x, y) {
+            if let (Ok(x), Ok(y)) = (x, y) {
                 match Ord::cmp(&x.ty, &y.ty) {
                     Ordering::Equal => compare(&x.name.to_string_lossy(), &y.name.to_string_lossy()),
                     order => order
@@ -32,28 +92,22 @@ impl SortDir {
             }
         }
 
-        block_in_place(move || {
-            let mut buf = readdir
-                .by_ref()
-                .take(SORTDIR_BUFF_LENGTH)
-                .map(|entry| entry.and_then(Entry::new))
-                .collect::<SmallVec<_>>();
-            buf.sort_unstable_by(|x, y| sort_by_entry(y, x));
-            SortDir { readdir, buf }
-        })
+        let load = Load { policy, root, filter };
+
+        let mut buf = load.batch(&mut readdir, SORTDIR_BUFF_LENGTH).await.unwrap_or_default();
+        buf.sort_unstable_by(|x, y| sort_by_entry(y, x));
+        SortDir { readdir, load, buf }
     }
-}
 
-impl Iterator for SortDir {
-    type Item = io::Result<Entry>;
+    pub async fn next_entry(&mut self) -> Option<io::Result<Entry>> {
+        let SortDir { readdir, load, buf } = self;
+
+        while buf.is_empty() {
+            *buf = load.batch(readdir, BATCH_LENGTH).await?;
+            buf.reverse();
+        }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.buf.pop()
-            .or_else(|| block_in_place(|| {
-                self.readdir
-                    .next()
-                    .map(|entry| entry.and_then(Entry::new))
-            }))
+        buf.pop()
     }
 }
 
@@ -80,44 +134,60 @@ impl fmt::Display for EntryType {
 }
 
 pub struct Entry {
-    pub metadata: Metadata,
     pub name: OsString,
-    pub ty: EntryType
+    pub ty: EntryType,
+
+    /// of what a followed symlink points to.
+    pub kind: Kind,
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+    pub blocked: bool
 }
 
 impl Entry {
     #[allow(clippy::needless_pass_by_value)]
-    pub fn new(entry: DirEntry) -> io::Result<Self> {
+    pub fn new(entry: DirEntry, policy: SymlinkPolicy, root: &Path) -> io::Result<Self> {
         let mut metadata = entry.metadata()?;
         let path = entry.path();
         let name = entry.file_name();
         let is_symlink = metadata.file_type().is_symlink();
-        if is_symlink {
+        let blocked = is_symlink && policy.check(root, &path).is_err();
+        if is_symlink && !blocked {
             metadata = path.metadata()?;
         }
 
-        let ty = if is_symlink {
-            EntryType::Symlink
-        } else {
-            let ty = metadata.file_type();
-            if ty.is_dir() {
-                EntryType::Dir
-            } else if ty.is_file() {
-                EntryType::File
-            } else if ty.is_symlink() {
-                EntryType::Symlink
-            } else {
-                EntryType::Other
-            }
+        Ok(Entry::with(name, is_symlink, blocked, Kind::of(&metadata), metadata.len(), metadata.modified().ok()))
+    }
+
+    /// Like [`Entry::new`], with one `statx(2)` through the ring.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    async fn statx(ring: &uring::Ring, entry: DirEntry, policy: SymlinkPolicy, root: &Path) -> io::Result<Self> {
+        let path = entry.path();
+        let name = entry.file_name();
+        let is_symlink = entry.file_type()?.is_symlink();
+        let blocked = is_symlink && block_in_place(|| policy.check(root, &path)).is_err();
+        let stat = ring.statx(&path, is_symlink && !blocked).await?;
+
+        Ok(Entry::with(name, is_symlink, blocked, stat.kind, stat.len, stat.modified))
+    }
+
+    fn with(name: OsString, is_symlink: bool, blocked: bool, kind: Kind, len: u64, modified: Option<SystemTime>)
+        -> Self
+    {
+        let ty = match (is_symlink, kind) {
+            (true, _) => EntryType::Symlink,
+            (false, Kind::Dir) => EntryType::Dir,
+            (false, Kind::File) => EntryType::File,
+            (false, Kind::Special) => EntryType::Other
         };
 
-        Ok(Entry { metadata, name, ty })
+        Entry { name, ty, kind, len, modified, blocked }
     }
 
     #[inline]
     pub fn path(&self) -> String {
         let mut p = encode_path(&self.name);
-        if self.metadata.is_dir() {
+        if self.kind == Kind::Dir {
             p.push('/');
         }
         p
@@ -125,17 +195,18 @@ impl Entry {
 
     #[inline]
     pub fn time(&self) -> io::Result<OffsetDateTime> {
-        let time = self.metadata.modified()?;
+        let time = self.modified
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "no modification time"))?;
         let time = time.duration_since(SystemTime::UNIX_EPOCH)
-            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+            .map_err(io::Error::other)?;
         OffsetDateTime::from_unix_timestamp(time.as_secs() as _)
-            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+            .map_err(io::Error::other)
 
     }
 
     #[inline]
     pub fn size(&self) -> String {
-        bytesize::to_string(self.metadata.len(), true)
+        bytesize::to_string(self.len, true)
     }
 }
 
@@ -148,7 +219,13 @@ impl Render for Entry {
                 td class="icon" { (self.ty) }
 
                 td class="link" {
-                    a href=(self.path()) { (self.name.to_string_lossy()) }
+                    @if self.blocked {
+                        del title="blocked by symlink policy" { (self.name.to_string_lossy()) }
+                    } @else if self.kind == Kind::Special {
+                        (self.name.to_string_lossy())
+                    } @else {
+                        a href=(self.path()) { (self.name.to_string_lossy()) }
+                    }
                 }
 
                 td class="time" {
@@ -179,12 +256,12 @@ impl Render for Entry {
 }
 
 #[inline]
-pub fn up(top: bool) -> Markup {
+pub fn up(parent: Option<&str>) -> Markup {
     html!{
         tr {
             td  class="icon" { "⤴️" }
             td  class="link" {
-                @if !top { a href=".." { ".." } }
+                @if let Some(parent) = parent { a href=(parent) { ".." } }
             }
         }
     }
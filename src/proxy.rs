@@ -0,0 +1,379 @@
+//! The PROXY protocol, versions 1 and 2, as sent by HAProxy and load balancers in front of us.
+//!
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use std::{ cmp, fs, io };
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{ Context, Poll };
+use std::io::IoSlice;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr };
+use bytes::{ Buf, BytesMut };
+use tokio::io::{ AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf };
+use crate::sendfile::AsyncSendfile;
+
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_HEADER_LEN: usize = 16;
+
+/// Whether a listener expects a PROXY header in front of each connection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProxyProtocol {
+    #[default]
+    Off,
+
+    /// take the header if a trusted proxy sends one, for migrating a balancer over.
+    Optional,
+
+    /// reject connections without a header.
+    Required
+}
+
+impl ProxyProtocol {
+    pub fn name(self) -> &'static str {
+        match self {
+            ProxyProtocol::Off => "off",
+            ProxyProtocol::Optional => "optional",
+            ProxyProtocol::Required => "required"
+        }
+    }
+}
+
+impl FromStr for ProxyProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(ProxyProtocol::Off),
+            "optional" => Ok(ProxyProtocol::Optional),
+            "required" => Ok(ProxyProtocol::Required),
+            _ => Err(format!("unknown proxy protocol mode: {}", s))
+        }
+    }
+}
+
+/// What the PROXY header said about the connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Proxy {
+    /// no header was sent.
+    None,
+
+    /// the balancer's own connection, such as a health check, or an address we can't represent.
+    Local,
+
+    /// the address of the client behind the balancer.
+    Client(SocketAddr)
+}
+
+/// A connection after its PROXY header, replaying whatever was read past it.
+pub struct ProxyStream<IO> {
+    buf: BytesMut,
+    io: IO
+}
+
+impl<IO> ProxyStream<IO>
+where IO: AsyncRead + AsyncWrite + Unpin
+{
+    pub async fn accept(mut io: IO, mode: ProxyProtocol) -> io::Result<(ProxyStream<IO>, Proxy)> {
+        let mut buf = BytesMut::new();
+
+        if mode == ProxyProtocol::Off {
+            return Ok((ProxyStream { buf, io }, Proxy::None));
+        }
+
+        loop {
+            match parse(&buf)? {
+                Parsed::Done(len, proxy) => {
+                    buf.advance(len);
+                    return Ok((ProxyStream { buf, io }, proxy));
+                },
+                Parsed::Absent if mode == ProxyProtocol::Required =>
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "proxy header required")),
+                Parsed::Absent => return Ok((ProxyStream { buf, io }, Proxy::None)),
+                Parsed::Partial => ()
+            }
+
+            if io.read_buf(&mut buf).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+}
+
+enum Parsed {
+    Partial,
+    Absent,
+    Done(usize, Proxy)
+}
+
+fn parse(buf: &[u8]) -> io::Result<Parsed> {
+    let is_prefix = |sig: &[u8]| {
+        let len = cmp::min(buf.len(), sig.len());
+        buf[..len] == sig[..len]
+    };
+
+    if buf.is_empty() {
+        Ok(Parsed::Partial)
+    } else if is_prefix(V2_SIGNATURE) {
+        parse_v2(buf)
+    } else if is_prefix(V1_PREFIX) {
+        parse_v1(buf)
+    } else {
+        Ok(Parsed::Absent)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("bad proxy header: {}", msg))
+}
+
+fn parse_v1(buf: &[u8]) -> io::Result<Parsed> {
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => end,
+        None if buf.len() >= V1_MAX_LEN => return Err(invalid("v1 line too long")),
+        None => return Ok(Parsed::Partial)
+    };
+    let line = std::str::from_utf8(&buf[V1_PREFIX.len()..end])
+        .map_err(|_| invalid("v1 line is not ascii"))?;
+    let mut fields = line.split(' ');
+
+    let proxy = match fields.next() {
+        Some("UNKNOWN") => Proxy::Local,
+        Some(proto @ ("TCP4" | "TCP6")) => {
+            let src = fields.next().and_then(|ip| ip.parse::<IpAddr>().ok());
+            let _dst = fields.next();
+            let port = fields.next().and_then(|port| port.parse::<u16>().ok());
+            match (src, port) {
+                (Some(ip), Some(port)) if ip.is_ipv4() == (proto == "TCP4") =>
+                    Proxy::Client(SocketAddr::new(ip, port)),
+                _ => return Err(invalid("v1 address"))
+            }
+        },
+        _ => return Err(invalid("v1 protocol"))
+    };
+
+    Ok(Parsed::Done(end + 2, proxy))
+}
+
+fn parse_v2(buf: &[u8]) -> io::Result<Parsed> {
+    if buf.len() < V2_HEADER_LEN {
+        return Ok(Parsed::Partial);
+    }
+
+    let ver_cmd = buf[12];
+    let family = buf[13];
+    let len = u16::from_be_bytes([buf[14], buf[15]]) as usize;
+
+    if ver_cmd >> 4 != 2 {
+        return Err(invalid("v2 version"));
+    }
+    if buf.len() < V2_HEADER_LEN + len {
+        return Ok(Parsed::Partial);
+    }
+
+    let addr = &buf[V2_HEADER_LEN..][..len];
+    let proxy = match (ver_cmd & 0xf, family) {
+        (0, _) => Proxy::Local,
+
+        // TCP over IPv4, then TCP over IPv6
+        (1, 0x11) if len >= 12 => {
+            let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+            let port = u16::from_be_bytes([addr[8], addr[9]]);
+            Proxy::Client(SocketAddr::new(ip.into(), port))
+        },
+        (1, 0x21) if len >= 36 => {
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&addr[..16]);
+            let ip = Ipv6Addr::from(ip);
+            let port = u16::from_be_bytes([addr[32], addr[33]]);
+            Proxy::Client(SocketAddr::new(ip.into(), port))
+        },
+        (1, 0x11 | 0x21) => return Err(invalid("v2 address too short")),
+        (1, _) => Proxy::Local,
+        _ => return Err(invalid("v2 command"))
+    };
+
+    Ok(Parsed::Done(V2_HEADER_LEN + len, proxy))
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for ProxyStream<IO> {
+    #[inline]
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.buf.is_empty() {
+            Pin::new(&mut this.io).poll_read(cx, buf)
+        } else {
+            let len = cmp::min(this.buf.len(), buf.remaining());
+            buf.put_slice(&this.buf[..len]);
+            this.buf.advance(len);
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for ProxyStream<IO> {
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+
+    #[inline]
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>])
+        -> Poll<io::Result<usize>>
+    {
+        Pin::new(&mut self.get_mut().io).poll_write_vectored(cx, bufs)
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+}
+
+impl<IO: AsyncSendfile + Unpin> AsyncSendfile for ProxyStream<IO> {
+    #[inline]
+    fn poll_sendfile(self: Pin<&mut Self>, cx: &mut Context<'_>, file: &fs::File, offset: u64, len: usize)
+        -> Poll<io::Result<usize>>
+    {
+        Pin::new(&mut self.get_mut().io).poll_sendfile(cx, file, offset, len)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl<IO: crate::ktls::KernelSocket> crate::ktls::KernelSocket for ProxyStream<IO> {
+    /// Not while bytes read past the header are still buffered.
+    fn kernel_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        match self.buf.is_empty() {
+            true => self.io.kernel_fd(),
+            false => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+    use super::*;
+
+    fn v2(cmd: u8, family: u8, addr: &[u8], tlvs: &[u8]) -> Vec<u8> {
+        let mut buf = V2_SIGNATURE.to_vec();
+        buf.push(0x20 | cmd);
+        buf.push(family);
+        buf.extend_from_slice(&((addr.len() + tlvs.len()) as u16).to_be_bytes());
+        buf.extend_from_slice(addr);
+        buf.extend_from_slice(tlvs);
+        buf
+    }
+
+    fn done(buf: &[u8]) -> (usize, Proxy) {
+        match parse(buf).unwrap() {
+            Parsed::Done(len, proxy) => (len, proxy),
+            Parsed::Partial => panic!("partial"),
+            Parsed::Absent => panic!("absent")
+        }
+    }
+
+    #[test]
+    fn v1() {
+        let line = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET";
+        assert_eq!(done(line), (line.len() - 3, Proxy::Client("192.0.2.1:56324".parse().unwrap())));
+
+        let line = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        assert_eq!(done(line), (line.len(), Proxy::Client("[2001:db8::1]:56324".parse().unwrap())));
+
+        assert_eq!(done(b"PROXY UNKNOWN\r\n"), (15, Proxy::Local));
+
+        assert!(matches!(parse(b"PRO").unwrap(), Parsed::Partial));
+        assert!(matches!(parse(b"PROXY TCP4 192.0.2.1").unwrap(), Parsed::Partial));
+        assert!(matches!(parse(b"GET / HTTP/1.1\r\n").unwrap(), Parsed::Absent));
+
+        assert!(parse(b"PROXY TCP4 2001:db8::1 2001:db8::2 1 2\r\n").is_err());
+        assert!(parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 x 443\r\n").is_err());
+        assert!(parse(b"PROXY UDP4 192.0.2.1 198.51.100.1 1 2\r\n").is_err());
+        assert!(parse(&[b"PROXY TCP4 ".as_ref(), &[b'1'; V1_MAX_LEN]].concat()).is_err());
+    }
+
+    #[test]
+    fn v2_addresses() {
+        let addr4 = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        let buf = v2(1, 0x11, &addr4, &[]);
+        assert_eq!(done(&buf), (28, Proxy::Client("192.0.2.1:56324".parse().unwrap())));
+
+        let mut addr6 = [0; 36];
+        addr6[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        addr6[32..].copy_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        let buf = v2(1, 0x21, &addr6, &[]);
+        assert_eq!(done(&buf), (52, Proxy::Client("[2001:db8::1]:56324".parse().unwrap())));
+
+        // a health check, and a unix socket we can't represent
+        assert_eq!(done(&v2(0, 0, &[], &[])), (16, Proxy::Local));
+        assert_eq!(done(&v2(1, 0x31, &[0; 216], &[])), (232, Proxy::Local));
+
+        assert!(parse(&v2(1, 0x11, &addr4[..8], &[])).is_err());
+        assert!(parse(&v2(2, 0x11, &addr4, &[])).is_err());
+
+        let mut buf = v2(1, 0x11, &addr4, &[]);
+        buf[12] = 0x11;
+        assert!(parse(&buf).is_err());
+    }
+
+    #[test]
+    fn v2_tlvs() {
+        let addr4 = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        let client = Proxy::Client("192.0.2.1:56324".parse().unwrap());
+
+        // PP2_TYPE_AUTHORITY, skipped along with the address
+        let tlv = [&[0x02, 0x00, 0x0b][..], b"example.com"].concat();
+        let buf = [v2(1, 0x11, &addr4, &tlv), b"GET".to_vec()].concat();
+        assert_eq!(done(&buf), (buf.len() - 3, client));
+
+        // a TLV that claims more than the header holds ends with the header all the same
+        let buf = v2(1, 0x11, &addr4, &[0x02, 0xff, 0xff, b'x']);
+        assert_eq!(done(&buf), (buf.len(), client));
+
+        // truncated anywhere, the rest is awaited
+        let buf = v2(1, 0x11, &addr4, &tlv);
+        for len in 1..buf.len() {
+            assert!(matches!(parse(&buf[..len]).unwrap(), Parsed::Partial), "{}", len);
+        }
+
+        // the largest length the header can state
+        let buf = v2(1, 0x11, &addr4, &vec![0; usize::from(u16::MAX) - addr4.len()]);
+        assert!(matches!(parse(&buf[..buf.len() - 1]).unwrap(), Parsed::Partial));
+        assert_eq!(done(&buf), (V2_HEADER_LEN + usize::from(u16::MAX), client));
+    }
+
+    #[tokio::test]
+    async fn accept() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n").await.unwrap();
+
+        let (mut stream, proxy) = ProxyStream::accept(server, ProxyProtocol::Required).await.unwrap();
+        assert_eq!(proxy, Proxy::Client("192.0.2.1:56324".parse().unwrap()));
+        let mut buf = [0; 16];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"GET / HTTP/1.1\r\n");
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let (_, proxy) = ProxyStream::accept(server, ProxyProtocol::Optional).await.unwrap();
+        assert_eq!(proxy, Proxy::None);
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        assert!(ProxyStream::accept(server, ProxyProtocol::Required).await.is_err());
+    }
+}
@@ -0,0 +1,75 @@
+use std::sync::Arc;
+use futures::future;
+use hyper::body::Incoming;
+use hyper::service::Service;
+use hyper::{ StatusCode, Request, Response };
+use http::HeaderValue;
+use http::header::LOCATION;
+use crate::body::ResponseBody as Body;
+use crate::tls::Challenges;
+use crate::{ acme_response, request_host, err_response };
+
+
+/// Answers plain HTTP requests with a permanent redirect to the HTTPS origin.
+#[derive(Clone)]
+pub struct Redirect {
+    /// the HTTPS port, left out of the location when it is 443.
+    pub port: u16,
+
+    /// pending ACME HTTP-01 challenges, answered instead of redirected.
+    pub acme: Option<Arc<Challenges>>
+}
+
+impl Redirect {
+    fn location<B>(&self, req: &Request<B>) -> Option<HeaderValue> {
+        let host = request_host(req)?;
+        let path = req.uri().path_and_query().map_or("/", |p| p.as_str());
+        let location = match self.port {
+            443 => format!("https://{}{}", host, path),
+            port => format!("https://{}:{}{}", host, port, path)
+        };
+
+        HeaderValue::from_str(&location).ok()
+    }
+}
+
+impl Service<Request<Incoming>> for Redirect {
+    type Response = Response<Body>;
+    type Error = !;
+    type Future = future::Ready<Result<Response<Body>, Self::Error>>;
+
+    fn call(&self, req: Request<Incoming>) -> Self::Future {
+        let span = info_span!("redirect", method=%req.method(), path=%req.uri().path());
+        let _enter = span.enter();
+
+        if let Some(resp) = self.acme.as_deref().and_then(|acme| acme_response(acme, &req)) {
+            return future::ok(resp);
+        }
+
+        let location = match self.location(&req) {
+            Some(location) => location,
+            None => {
+                info!("redirect/no host");
+                return future::ok(err_response(StatusCode::BAD_REQUEST, format_args!("Missing host")));
+            }
+        };
+
+        debug!(?location, "redirect");
+        let mut resp = err_response(StatusCode::PERMANENT_REDIRECT, format_args!("Moved to HTTPS"));
+        resp.headers_mut().insert(LOCATION, location);
+        future::ok(resp)
+    }
+}
+
+/// Build a `Strict-Transport-Security` value.
+pub fn hsts(max_age: u64, include_subdomains: bool, preload: bool) -> HeaderValue {
+    let mut value = format!("max-age={}", max_age);
+    if include_subdomains {
+        value.push_str("; includeSubDomains");
+    }
+    if preload {
+        value.push_str("; preload");
+    }
+
+    HeaderValue::from_str(&value).expect("hsts value is ascii")
+}
@@ -0,0 +1,300 @@
+//! Zero-copy file bodies for plaintext HTTP/1 connections.
+//!
+//! hyper only writes what a body hands it, so a file body hands it placeholder
+//! chunks pointing into [`PLACEHOLDER`], and [`SendfileStream`] sends as many bytes
+//! of the queued file with `sendfile(2)` whenever it is asked to write them.
+
+use std::{ cmp, fs, io };
+use std::pin::Pin;
+use std::sync::{ Arc, Mutex };
+use std::collections::VecDeque;
+use std::io::IoSlice;
+use std::task::{ Context, Poll };
+use bytes::Bytes;
+use tokio::io::{ AsyncRead, AsyncWrite, ReadBuf };
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+
+const CHUNK: usize = 1 << 20;
+
+/// Never read, only its address matters.
+static PLACEHOLDER: [u8; CHUNK] = [0; CHUNK];
+
+fn is_placeholder(buf: &[u8]) -> bool {
+    let start = PLACEHOLDER.as_ptr() as usize;
+    let ptr = buf.as_ptr() as usize;
+    !buf.is_empty() && (start..start + CHUNK).contains(&ptr)
+}
+
+/// A socket that can send straight from a file.
+pub trait AsyncSendfile {
+    fn poll_sendfile(self: Pin<&mut Self>, cx: &mut Context<'_>, file: &fs::File, offset: u64, len: usize)
+        -> Poll<io::Result<usize>>;
+}
+
+struct Job {
+    file: fs::File,
+    offset: u64,
+    remaining: u64
+}
+
+/// The files of one connection, in the order their bodies are written.
+#[derive(Default)]
+pub struct Sendfile {
+    queue: Mutex<VecDeque<Job>>
+}
+
+/// A body of `range` of `file`, made of placeholders.
+pub struct FileBody {
+    slot: Arc<Sendfile>,
+    job: Option<Job>,
+    remaining: u64
+}
+
+impl FileBody {
+    pub fn new(slot: Arc<Sendfile>, file: fs::File, offset: u64, len: u64) -> FileBody {
+        FileBody {
+            slot,
+            job: Some(Job { file, offset, remaining: len }),
+            remaining: len
+        }
+    }
+
+    /// Queues the file when hyper starts on the body, which is after every response before it.
+    pub fn next_chunk(&mut self) -> Option<Bytes> {
+        if let Some(job) = self.job.take() {
+            self.slot.queue.lock().unwrap().push_back(job);
+        }
+
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let len = cmp::min(self.remaining, CHUNK as u64) as usize;
+        self.remaining -= len as u64;
+        Some(Bytes::from_static(&PLACEHOLDER[..len]))
+    }
+}
+
+/// Sends placeholders from the file queued for them, everything else as usual.
+pub struct SendfileStream<IO> {
+    io: IO,
+    slot: Option<Arc<Sendfile>>
+}
+
+impl<IO> SendfileStream<IO> {
+    /// Without a `slot` this only passes writes on.
+    pub fn new(io: IO, slot: Option<Arc<Sendfile>>) -> SendfileStream<IO> {
+        SendfileStream { io, slot }
+    }
+}
+
+impl<IO: AsyncSendfile + Unpin> SendfileStream<IO> {
+    fn poll_file(&mut self, cx: &mut Context<'_>, len: usize) -> Poll<io::Result<usize>> {
+        let slot = match self.slot.as_ref() {
+            Some(slot) => slot,
+            None => return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "no file to send")))
+        };
+        let mut queue = slot.queue.lock().unwrap();
+        let job = match queue.front_mut() {
+            Some(job) => job,
+            None => return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "no file to send")))
+        };
+
+        let len = cmp::min(len as u64, job.remaining) as usize;
+        let n = match Pin::new(&mut self.io).poll_sendfile(cx, &job.file, job.offset, len) {
+            Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into())),
+            Poll::Ready(Ok(n)) => n,
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Pending => return Poll::Pending
+        };
+
+        job.offset += n as u64;
+        job.remaining -= n as u64;
+        if job.remaining == 0 {
+            queue.pop_front();
+        }
+
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for SendfileStream<IO> {
+    #[inline]
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_read(cx, buf)
+    }
+}
+
+impl<IO: AsyncWrite + AsyncSendfile + Unpin> AsyncWrite for SendfileStream<IO> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if is_placeholder(buf) {
+            this.poll_file(cx, buf.len())
+        } else {
+            Pin::new(&mut this.io).poll_write(cx, buf)
+        }
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>])
+        -> Poll<io::Result<usize>>
+    {
+        let this = self.get_mut();
+
+        // write up to the first placeholder, or send from the file if it comes first
+        match bufs.iter().position(|buf| is_placeholder(buf)) {
+            Some(0) => this.poll_file(cx, bufs[0].len()),
+            Some(i) => Pin::new(&mut this.io).poll_write_vectored(cx, &bufs[..i]),
+            None => Pin::new(&mut this.io).poll_write_vectored(cx, bufs)
+        }
+    }
+
+    /// Always, so hyper queues body chunks as they are instead of copying them.
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn sendfile(out: &impl std::os::unix::io::AsRawFd, file: &fs::File, offset: u64, len: usize) -> io::Result<usize> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut offset = offset as libc::off_t;
+    let n = unsafe { libc::sendfile(out.as_raw_fd(), file.as_raw_fd(), &mut offset, len) };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+macro_rules! socket_sendfile {
+    ( $socket:ty ) => {
+        impl AsyncSendfile for $socket {
+            #[cfg(target_os = "linux")]
+            fn poll_sendfile(self: Pin<&mut Self>, cx: &mut Context<'_>, file: &fs::File, offset: u64, len: usize)
+                -> Poll<io::Result<usize>>
+            {
+                loop {
+                    match self.poll_write_ready(cx) {
+                        Poll::Ready(Ok(())) => (),
+                        Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                        Poll::Pending => return Poll::Pending
+                    }
+
+                    // reading the file may wait on the disk
+                    let send = || tokio::task::block_in_place(|| sendfile(&*self, file, offset, len));
+                    match self.try_io(tokio::io::Interest::WRITABLE, send) {
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                        result => return Poll::Ready(result)
+                    }
+                }
+            }
+
+            #[cfg(not(target_os = "linux"))]
+            fn poll_sendfile(self: Pin<&mut Self>, _: &mut Context<'_>, _: &fs::File, _: u64, _: usize)
+                -> Poll<io::Result<usize>>
+            {
+                Poll::Ready(Err(io::ErrorKind::Unsupported.into()))
+            }
+        }
+    }
+}
+
+socket_sendfile!(TcpStream);
+#[cfg(unix)]
+socket_sendfile!(UnixStream);
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{ AsyncReadExt, AsyncWriteExt };
+    use tokio::net::TcpListener;
+    use crate::WebDir;
+    use crate::tests::{ temp_dir, serve, get };
+    use super::*;
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn placeholders_from_file() {
+        let dir = temp_dir("sendfile-stream");
+        let body = content(CHUNK * 2 + 12345);
+        fs::write(dir.join("file"), &body).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (tcp, _) = listener.accept().await.unwrap();
+
+        let slot = Arc::new(Sendfile::default());
+        let mut stream = SendfileStream::new(tcp, Some(slot.clone()));
+        let send = async move {
+            // whole, then a range, with plain writes around them
+            for (offset, len) in [(0, body.len() as u64), (100, CHUNK as u64 + 1)] {
+                let file = fs::File::open(dir.join("file")).unwrap();
+                let mut body = FileBody::new(slot.clone(), file, offset, len);
+                stream.write_all(b"head").await.unwrap();
+                while let Some(chunk) = body.next_chunk() {
+                    stream.write_all(&chunk).await.unwrap();
+                }
+            }
+            stream.write_all(b"tail").await.unwrap();
+            stream.shutdown().await.unwrap();
+            fs::remove_dir_all(&dir).unwrap();
+        };
+        let recv = async {
+            let mut buf = Vec::new();
+            client.read_to_end(&mut buf).await.unwrap();
+            buf
+        };
+        let ((), received) = tokio::join!(send, recv);
+
+        let body = content(CHUNK * 2 + 12345);
+        let mut expected = b"head".to_vec();
+        expected.extend_from_slice(&body);
+        expected.extend_from_slice(b"head");
+        expected.extend_from_slice(&body[100..][..CHUNK + 1]);
+        expected.extend_from_slice(b"tail");
+        assert!(received == expected, "{} bytes, expected {}", received.len(), expected.len());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn served_with_ranges() {
+        let root = temp_dir("sendfile-served");
+        let body = content(CHUNK * 3 + 777);
+        fs::write(root.join("file.bin"), &body).unwrap();
+
+        let webdir = WebDir::new(Arc::from(root.as_path()), false).unwrap();
+        assert!(webdir.zero_copy || !cfg!(target_os = "linux"));
+        let addr = serve(webdir).await;
+
+        let (head, received) = get(addr, "/file.bin", &[]).await;
+        assert!(head.starts_with("http/1.1 200"), "{}", head);
+        assert!(received == body, "{} bytes, expected {}", received.len(), body.len());
+
+        let (head, received) = get(addr, "/file.bin", &["Range: bytes=1000-2099999"]).await;
+        assert!(head.starts_with("http/1.1 206"), "{}", head);
+        assert!(received == body[1000..2100000], "{} bytes", received.len());
+
+        let (head, received) = get(addr, "/file.bin", &["Range: bytes=-10"]).await;
+        assert!(head.starts_with("http/1.1 206"), "{}", head);
+        assert_eq!(received, body[body.len() - 10..]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
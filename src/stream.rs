@@ -1,77 +1,254 @@
+use std::{ cmp, fs };
 use std::pin::Pin;
 use std::marker::Unpin;
 use std::io::{ self, IoSlice };
 use std::task::{ Context, Poll };
 use tokio::io::{ AsyncRead, AsyncWrite, ReadBuf };
 use tokio_rustls::{ TlsAcceptor, server::TlsStream };
+use crate::tls::{ ClientCert, ACME_TLS_ALPN };
+use crate::sendfile::AsyncSendfile;
+#[cfg(target_os = "linux")]
+use crate::ktls::{ self, Ktls, KernelSocket };
 
 
+#[allow(clippy::large_enum_variant)]
 pub enum Stream<IO> {
     Socket(IO),
-    Tls(TlsStream<IO>)
+    Tls(TlsStream<Cork<IO>>),
+
+    /// TLS the kernel took over after the handshake.
+    #[cfg(target_os = "linux")]
+    Ktls(Ktls<IO>)
+}
+
+macro_rules! stream {
+    ( $self:expr, $io:ident => $e:expr ) => {
+        match $self {
+            Stream::Socket($io) => $e,
+            Stream::Tls($io) => $e,
+            #[cfg(target_os = "linux")]
+            Stream::Ktls($io) => $e
+        }
+    }
 }
 
 impl<IO> Stream<IO>
 where IO: private::AsyncIo
 {
-    pub async fn new(io: IO, accept: Option<TlsAcceptor>) -> io::Result<Stream<IO>> {
+    /// With `cork`, the handshake reads whole records only, as [`Stream::into_ktls`] needs.
+    pub async fn new(io: IO, accept: Option<TlsAcceptor>, cork: bool) -> io::Result<Stream<IO>> {
         Ok(match accept {
-            Some(acceptor) => Stream::Tls(acceptor.accept(io).await?),
+            Some(acceptor) => {
+                let mut tls = acceptor.accept(Cork::new(io, cork)).await?;
+                if !cork {
+                    tls.get_mut().0.uncork();
+                }
+                Stream::Tls(tls)
+            },
             None => Stream::Socket(io)
         })
     }
+
+    pub fn server_name(&self) -> Option<&str> {
+        match self {
+            Stream::Socket(_) => None,
+            Stream::Tls(io) => io.get_ref().1.server_name(),
+            #[cfg(target_os = "linux")]
+            Stream::Ktls(io) => io.server_name.as_deref()
+        }
+    }
+
+    /// Whether this was a TLS-ALPN-01 validation handshake, which carries no requests.
+    pub fn is_acme_challenge(&self) -> bool {
+        match self {
+            Stream::Socket(_) => false,
+            Stream::Tls(io) => io.get_ref().1.alpn_protocol() == Some(ACME_TLS_ALPN),
+            #[cfg(target_os = "linux")]
+            Stream::Ktls(io) => io.alpn.as_deref() == Some(ACME_TLS_ALPN)
+        }
+    }
+
+    pub fn client_cert(&self) -> Option<ClientCert> {
+        match self {
+            Stream::Socket(_) => None,
+            Stream::Tls(io) => io.get_ref().1
+                .peer_certificates()?
+                .first()
+                .and_then(ClientCert::from_der),
+            #[cfg(target_os = "linux")]
+            Stream::Ktls(io) => io.cert.as_ref().and_then(ClientCert::from_der)
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        !matches!(self, Stream::Socket(_))
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl<IO> Stream<IO>
+where IO: private::AsyncIo + KernelSocket
+{
+    /// Hand a corked TLS stream to the kernel, keeping rustls where the kernel can't.
+    pub fn into_ktls(self) -> io::Result<Stream<IO>> {
+        match self {
+            Stream::Tls(tls) => Ok(match ktls::setup(tls)? {
+                Ok(ktls) => Stream::Ktls(ktls),
+                Err(mut tls) => {
+                    tls.get_mut().0.uncork();
+                    Stream::Tls(tls)
+                }
+            }),
+            stream => Ok(stream)
+        }
+    }
 }
 
 impl<IO: private::AsyncIo> AsyncRead for Stream<IO> {
     #[inline]
     fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
-        match self.get_mut() {
-            Stream::Socket(io) => Pin::new(io).poll_read(cx, buf),
-            Stream::Tls(io) => Pin::new(io).poll_read(cx, buf)
-        }
+        stream!(self.get_mut(), io => Pin::new(io).poll_read(cx, buf))
     }
 }
 
 impl<IO: private::AsyncIo> AsyncWrite for Stream<IO> {
     #[inline]
     fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
-        match self.get_mut() {
-            Stream::Socket(io) => Pin::new(io).poll_write(cx, buf),
-            Stream::Tls(io) => Pin::new(io).poll_write(cx, buf)
-        }
+        stream!(self.get_mut(), io => Pin::new(io).poll_write(cx, buf))
     }
 
     #[inline]
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        match self.get_mut() {
-            Stream::Socket(io) => Pin::new(io).poll_flush(cx),
-            Stream::Tls(io) => Pin::new(io).poll_flush(cx)
-        }
+        stream!(self.get_mut(), io => Pin::new(io).poll_flush(cx))
     }
 
     #[inline]
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        match self.get_mut() {
-            Stream::Socket(io) => Pin::new(io).poll_shutdown(cx),
-            Stream::Tls(io) => Pin::new(io).poll_shutdown(cx)
-        }
+        stream!(self.get_mut(), io => Pin::new(io).poll_shutdown(cx))
     }
 
     #[inline]
     fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>])
         -> Poll<io::Result<usize>>
+    {
+        stream!(self.get_mut(), io => Pin::new(io).poll_write_vectored(cx, bufs))
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        stream!(self, io => io.is_write_vectored())
+    }
+}
+
+impl<IO: private::AsyncIo + AsyncSendfile> AsyncSendfile for Stream<IO> {
+    /// Not through rustls, which needs the bytes.
+    fn poll_sendfile(self: Pin<&mut Self>, cx: &mut Context<'_>, file: &fs::File, offset: u64, len: usize)
+        -> Poll<io::Result<usize>>
     {
         match self.get_mut() {
-            Stream::Socket(io) => Pin::new(io).poll_write_vectored(cx, bufs),
-            Stream::Tls(io) => Pin::new(io).poll_write_vectored(cx, bufs)
+            Stream::Socket(io) => Pin::new(io).poll_sendfile(cx, file, offset, len),
+            Stream::Tls(_) => Poll::Ready(Err(io::ErrorKind::Unsupported.into())),
+            #[cfg(target_os = "linux")]
+            Stream::Ktls(io) => Pin::new(io).poll_sendfile(cx, file, offset, len)
+        }
+    }
+}
+
+const RECORD_HEADER_LEN: usize = 5;
+
+/// Reads no further than the end of the current TLS record while corked,
+/// so a finished handshake leaves nothing half read in rustls.
+pub struct Cork<IO> {
+    io: IO,
+    corked: bool,
+    header: [u8; RECORD_HEADER_LEN],
+    have: usize,
+    left: usize
+}
+
+impl<IO> Cork<IO> {
+    fn new(io: IO, corked: bool) -> Cork<IO> {
+        Cork { io, corked, header: [0; RECORD_HEADER_LEN], have: 0, left: 0 }
+    }
+
+    fn uncork(&mut self) {
+        self.corked = false;
+    }
+
+    pub fn into_inner(self) -> IO {
+        self.io
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for Cork<IO> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if !this.corked {
+            return Pin::new(&mut this.io).poll_read(cx, buf);
+        }
+
+        let want = if this.left > 0 { this.left } else { RECORD_HEADER_LEN - this.have };
+        let want = cmp::min(want, buf.remaining());
+        let mut part = ReadBuf::new(buf.initialize_unfilled_to(want));
+        match Pin::new(&mut this.io).poll_read(cx, &mut part) {
+            Poll::Ready(Ok(())) => (),
+            poll => return poll
         }
+        let n = part.filled().len();
+
+        if this.left > 0 {
+            this.left -= n;
+        } else {
+            this.header[this.have..][..n].copy_from_slice(part.filled());
+            this.have += n;
+            if this.have == RECORD_HEADER_LEN {
+                this.left = usize::from(u16::from_be_bytes([this.header[3], this.header[4]]));
+                this.have = 0;
+            }
+        }
+
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for Cork<IO> {
+    #[inline]
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+
+    #[inline]
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>])
+        -> Poll<io::Result<usize>>
+    {
+        Pin::new(&mut self.get_mut().io).poll_write_vectored(cx, bufs)
     }
 
     #[inline]
     fn is_write_vectored(&self) -> bool {
-        match &*self {
-            Stream::Socket(io) => io.is_write_vectored(),
-            Stream::Tls(io) => io.is_write_vectored()
+        self.io.is_write_vectored()
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl<IO: KernelSocket> KernelSocket for Cork<IO> {
+    /// Only between records, and while still corked, since rustls has read ahead otherwise.
+    fn kernel_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        match self.corked && self.have == 0 && self.left == 0 {
+            true => self.io.kernel_fd(),
+            false => None
         }
     }
 }
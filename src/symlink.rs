@@ -0,0 +1,144 @@
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+#[cfg(unix)]
+use std::os::unix::io::BorrowedFd;
+
+
+/// How symlinks below the root are treated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    /// follow every symlink, wherever it points.
+    Follow,
+
+    /// follow a symlink only if the resolved target stays under the root.
+    #[default]
+    Contained,
+
+    /// never follow symlinks.
+    Never
+}
+
+impl SymlinkPolicy {
+    /// Check `path`, which lexically lies under `root`, against the policy.
+    ///
+    /// `root` must be canonical.
+    pub fn check(self, root: &Path, path: &Path) -> io::Result<()> {
+        match self {
+            SymlinkPolicy::Follow => Ok(()),
+            SymlinkPolicy::Contained => if path.canonicalize()?.starts_with(root) {
+                Ok(())
+            } else {
+                Err(blocked())
+            },
+            SymlinkPolicy::Never => {
+                let relative = path.strip_prefix(root)
+                    .map_err(|_| blocked())?;
+                let mut sum = root.to_path_buf();
+
+                for next in relative.components() {
+                    sum.push(next);
+                    if sum.symlink_metadata()?.file_type().is_symlink() {
+                        return Err(blocked());
+                    }
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Check `fd`, just opened from `path`, against the policy.
+    ///
+    /// A symlink swapped in between [`SymlinkPolicy::check`] and the open
+    /// would be followed anyway, so what counts is where the open really went.
+    #[cfg(unix)]
+    pub fn check_opened(self, root: &Path, path: &Path, fd: BorrowedFd<'_>) -> io::Result<()> {
+        if self == SymlinkPolicy::Follow {
+            return Ok(());
+        }
+
+        // where the kernel says the file is, if `/proc` is mounted
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::io::AsRawFd;
+
+            if let Ok(real) = fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd())) {
+                let allowed = match self {
+                    SymlinkPolicy::Contained => real.starts_with(root),
+                    _ => real == path
+                };
+                return if allowed { Ok(()) } else { Err(blocked()) };
+            }
+        }
+
+        // otherwise it has to be the file an allowed `path` leads to now
+        self.check(root, path)?;
+        let opened = fs::File::from(fd.try_clone_to_owned()?).metadata()?;
+        let allowed = path.metadata()?;
+
+        if (opened.dev(), opened.ino()) == (allowed.dev(), allowed.ino()) {
+            Ok(())
+        } else {
+            Err(blocked())
+        }
+    }
+}
+
+fn blocked() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "symlink blocked by policy")
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "contained" => Ok(SymlinkPolicy::Contained),
+            "never" => Ok(SymlinkPolicy::Never),
+            _ => Err(format!("unknown symlink policy: {}", s))
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::{ env, process };
+    use std::os::unix::io::AsFd;
+
+    #[test]
+    fn opened_through_a_swapped_symlink() {
+        let dir = env::temp_dir().join(format!("webdir-symlink-{}", process::id()));
+        let root = dir.join("root");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(dir.join("secret"), "secret").unwrap();
+        fs::write(root.join("file"), "file").unwrap();
+        let root = root.canonicalize().unwrap();
+        let path = root.join("swapped");
+
+        // a symlink leading out, opened after the check let a plain file through
+        std::os::unix::fs::symlink(dir.join("secret"), &path).unwrap();
+        let outside = fs::File::open(&path).unwrap();
+        assert!(SymlinkPolicy::Contained.check_opened(&root, &path, outside.as_fd()).is_err());
+        assert!(SymlinkPolicy::Never.check_opened(&root, &path, outside.as_fd()).is_err());
+        assert!(SymlinkPolicy::Follow.check_opened(&root, &path, outside.as_fd()).is_ok());
+
+        // one staying below the root
+        fs::remove_file(&path).unwrap();
+        std::os::unix::fs::symlink(root.join("file"), &path).unwrap();
+        let inside = fs::File::open(&path).unwrap();
+        assert!(SymlinkPolicy::Contained.check_opened(&root, &path, inside.as_fd()).is_ok());
+        assert!(SymlinkPolicy::Never.check_opened(&root, &path, inside.as_fd()).is_err());
+
+        let plain = fs::File::open(root.join("file")).unwrap();
+        assert!(SymlinkPolicy::Never.check_opened(&root, &root.join("file"), plain.as_fd()).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+//! Socket activation and service notification, following `sd_listen_fds(3)` and `sd_notify(3)`.
+
+use std::{ env, io, process };
+use std::time::Duration;
+use std::os::unix::io::{ FromRawFd, OwnedFd, RawFd };
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::UnixDatagram;
+use crate::listener::Listener;
+
+
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The socket activation variables.
+pub struct ListenEnv {
+    fds: Option<String>,
+    pid: Option<String>,
+    names: String
+}
+
+impl ListenEnv {
+    /// Read and remove the variables, so child processes don't pick them up.
+    ///
+    /// Changing the environment races with other threads, so call it before the runtime starts.
+    pub fn take() -> ListenEnv {
+        let env = ListenEnv {
+            fds: env::var("LISTEN_FDS").ok(),
+            pid: env::var("LISTEN_PID").ok(),
+            names: env::var("LISTEN_FDNAMES").unwrap_or_default()
+        };
+
+        for name in ["LISTEN_FDS", "LISTEN_PID", "LISTEN_FDNAMES"] {
+            env::remove_var(name);
+        }
+
+        env
+    }
+
+    /// Listening sockets passed by the service manager, with their `FileDescriptorName=`.
+    pub fn listen_fds(self) -> io::Result<Vec<(String, Listener)>> {
+        let fds = match self.fds {
+            Some(fds) if self.pid.and_then(|pid| pid.parse().ok()) == Some(process::id()) => fds,
+            _ => return Ok(Vec::new())
+        };
+        let count: RawFd = fds.parse()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, format!("bad LISTEN_FDS: {}", err)))?;
+        let names = self.names.split(':').collect::<Vec<_>>();
+
+        (0..count)
+            .map(|i| {
+                // the service manager hands these over to us, nothing else owns them
+                let fd = unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START + i) };
+                let name = names.get(i as usize)
+                    .filter(|name| !name.is_empty())
+                    .unwrap_or(&"unknown");
+
+                Ok((name.to_string(), Listener::from_fd(fd)?))
+            })
+            .collect()
+    }
+}
+
+/// Send `state`, such as `READY=1`, to the service manager if there is one.
+pub fn notify(state: &str) -> io::Result<()> {
+    let path = match env::var_os("NOTIFY_SOCKET") {
+        Some(path) => path,
+        None => return Ok(())
+    };
+    let socket = UnixDatagram::unbound()?;
+
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        Some(name) => {
+            #[cfg(target_os = "android")]
+            use std::os::android::net::SocketAddrExt;
+            #[cfg(target_os = "linux")]
+            use std::os::linux::net::SocketAddrExt;
+
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        },
+        _ => {
+            socket.send_to(state.as_bytes(), &path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// How often to send `WATCHDOG=1`, half of the timeout the service manager expects.
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse().ok() != Some(process::id()) {
+            return None;
+        }
+    }
+
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notify_and_watchdog() {
+        let dir = env::temp_dir().join(format!("webdir-notify-{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let _ = std::fs::remove_file(&path);
+        let socket = UnixDatagram::bind(&path).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // the only test touching these variables
+        env::set_var("NOTIFY_SOCKET", &path);
+        env::set_var("WATCHDOG_USEC", "3000000");
+        env::set_var("WATCHDOG_PID", process::id().to_string());
+
+        let mut buf = [0; 64];
+        notify("READY=1").unwrap();
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+
+        assert_eq!(watchdog_interval(), Some(Duration::from_millis(1500)));
+        notify("WATCHDOG=1").unwrap();
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"WATCHDOG=1");
+
+        env::set_var("WATCHDOG_PID", "1");
+        assert_eq!(watchdog_interval(), None);
+
+        for name in ["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"] {
+            env::remove_var(name);
+        }
+        notify("READY=1").unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,182 @@
+use std::cmp::Reverse;
+use std::sync::{ Arc, Mutex };
+use std::path::{ Path, PathBuf };
+use tokio::time::{ self, Duration, Instant };
+use crate::utils::path_canonicalize;
+
+
+/// A token bucket of `rate` bytes a second, holding at most a second's worth.
+///
+/// Takers may run into debt, so a chunk larger than the bucket still goes out,
+/// and everyone after it waits the debt off.
+pub struct Bucket {
+    rate: u64,
+    state: Mutex<State>
+}
+
+struct State {
+    tokens: f64,
+    last: Instant
+}
+
+impl Bucket {
+    pub fn new(rate: u64) -> Bucket {
+        Bucket {
+            rate,
+            state: Mutex::new(State { tokens: rate as f64, last: Instant::now() })
+        }
+    }
+
+    /// Wait until `len` bytes may go out.
+    pub async fn take(&self, len: usize) {
+        let rate = self.rate as f64;
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(state.last).as_secs_f64() * rate;
+            state.tokens = (state.tokens + refill).min(rate) - len as f64;
+            state.last = now;
+
+            if state.tokens < 0.0 {
+                Duration::from_secs_f64(-state.tokens / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            time::sleep(wait).await;
+        }
+    }
+}
+
+/// The buckets of a connection's path limits, made once it first needs them.
+#[derive(Default)]
+pub struct PathBuckets(Mutex<Vec<(PathBuf, Arc<Bucket>)>>);
+
+impl PathBuckets {
+    fn get(&self, prefix: &Path, rate: u64) -> Arc<Bucket> {
+        let mut buckets = self.0.lock().unwrap();
+
+        match buckets.iter().find(|(p, _)| p == prefix) {
+            Some((_, bucket)) => bucket.clone(),
+            None => {
+                let bucket = Arc::new(Bucket::new(rate));
+                buckets.push((prefix.to_owned(), bucket.clone()));
+                bucket
+            }
+        }
+    }
+}
+
+/// Bandwidth limits for file bodies, directory listings are never paced.
+#[derive(Default)]
+pub struct Throttle {
+    /// shared by every connection.
+    pub global: Option<Arc<Bucket>>,
+
+    /// bytes a second for each connection.
+    pub per_conn: Option<u64>,
+
+    /// bytes a second for each connection below a prefix, instead of the connection limit,
+    /// 0 lifts the connection limit. The longest matching prefix wins.
+    paths: Vec<(PathBuf, u64)>
+}
+
+impl Throttle {
+    pub fn set_path(&mut self, prefix: &str, rate: u64) {
+        let (_, prefix) = path_canonicalize(Path::new("/"), prefix);
+        self.paths.retain(|(p, _)| *p != prefix);
+        self.paths.push((prefix, rate));
+        self.paths.sort_by_key(|(p, _)| Reverse(p.components().count()));
+    }
+
+    /// A bucket for a new connection, if there is a per-connection limit.
+    pub fn connection(&self) -> Option<Arc<Bucket>> {
+        self.per_conn.map(|rate| Arc::new(Bucket::new(rate)))
+    }
+
+    /// The buckets a body for `path`, the decoded request path, is paced by.
+    pub fn buckets(&self, path: &Path, conn: Option<&Arc<Bucket>>, paths: &PathBuckets) -> Vec<Arc<Bucket>> {
+        let (_, path) = path_canonicalize(Path::new("/"), path);
+        let mut buckets = self.global.iter().cloned().collect::<Vec<_>>();
+
+        match self.paths.iter().find(|(prefix, _)| path.starts_with(prefix)) {
+            Some((_, 0)) => (),
+            Some((prefix, rate)) => buckets.push(paths.get(prefix, *rate)),
+            None => buckets.extend(conn.cloned())
+        }
+
+        buckets
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// How long `take(len)` waits.
+    async fn waits(bucket: &Bucket, len: usize) -> Duration {
+        let start = Instant::now();
+        bucket.take(len).await;
+        Instant::now() - start
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bucket() {
+        let bucket = Bucket::new(1000);
+
+        assert_eq!(waits(&bucket, 1000).await, Duration::ZERO);
+        assert_eq!(waits(&bucket, 500).await, Duration::from_millis(500));
+
+        // a chunk larger than the bucket runs into debt
+        assert_eq!(waits(&bucket, 2000).await, Duration::from_secs(2));
+
+        // idle time refills no more than a second's worth
+        time::sleep(Duration::from_secs(10)).await;
+        assert_eq!(waits(&bucket, 1000).await, Duration::ZERO);
+        assert_eq!(waits(&bucket, 1000).await, Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn buckets_by_path() {
+        let mut throttle = Throttle {
+            global: Some(Arc::new(Bucket::new(10_000))),
+            per_conn: Some(1000),
+            ..Throttle::default()
+        };
+        throttle.set_path("/big", 100);
+        throttle.set_path("big/bigger/", 50);
+        throttle.set_path("/free", 0);
+
+        let global = throttle.global.clone().unwrap();
+        let (conn, paths) = (throttle.connection().unwrap(), PathBuckets::default());
+        let buckets = |path: &str, paths: &PathBuckets| throttle.buckets(Path::new(path), Some(&conn), paths);
+
+        let plain = buckets("/file", &paths);
+        assert_eq!(plain.len(), 2);
+        assert!(Arc::ptr_eq(&plain[0], &global));
+        assert!(Arc::ptr_eq(&plain[1], &conn));
+
+        let free = buckets("/free/file", &paths);
+        assert_eq!(free.len(), 1);
+        assert!(Arc::ptr_eq(&free[0], &global));
+
+        let big = buckets("/big/file", &paths);
+        assert_eq!(big.len(), 2);
+        assert_eq!(big[1].rate, 100);
+        assert_eq!(buckets("/big/bigger/file", &paths)[1].rate, 50);
+        assert_eq!(buckets("/big/../file", &paths)[1].rate, 1000);
+
+        // kept for the connection, so a new request doesn't start with a full bucket
+        let again = buckets("/big/other", &paths);
+        assert!(Arc::ptr_eq(&big[1], &again[1]));
+        assert_eq!(waits(&big[1], 100).await, Duration::ZERO);
+        assert_eq!(waits(&again[1], 100).await, Duration::from_secs(1));
+
+        // but not shared with other connections
+        let other = buckets("/big/file", &PathBuckets::default());
+        assert!(!Arc::ptr_eq(&big[1], &other[1]));
+        assert_eq!(waits(&other[1], 100).await, Duration::ZERO);
+    }
+}
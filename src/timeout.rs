@@ -0,0 +1,275 @@
+use std::{ fmt, fs, io };
+use std::sync::Arc;
+use std::error::Error;
+use std::pin::Pin;
+use std::future::Future;
+use std::time::Duration;
+use std::task::{ Context, Poll };
+use std::io::IoSlice;
+use std::sync::atomic::{ self, AtomicU64, AtomicUsize };
+use futures::task::AtomicWaker;
+use tokio::io::{ AsyncRead, AsyncWrite, ReadBuf };
+use tokio::time::{ self, Instant, Sleep };
+use crate::sendfile::AsyncSendfile;
+
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timeout {
+    /// the PROXY header and TLS handshake.
+    Handshake,
+
+    /// an HTTP/1 request head, including the wait for it on a kept-alive connection.
+    Header,
+
+    /// no traffic at all while no response is in flight.
+    Idle,
+
+    /// the client stopped taking what we send.
+    Write
+}
+
+/// The error a connection or body fails with when we give up on it.
+#[derive(Debug)]
+struct TimedOut(Timeout);
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} timeout", self.0)
+    }
+}
+
+impl Error for TimedOut {}
+
+pub(crate) fn timed_out(kind: Timeout) -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, TimedOut(kind))
+}
+
+/// How long to wait on clients, `None` waits forever.
+#[derive(Default)]
+pub struct Timeouts {
+    pub handshake: Option<Duration>,
+    pub header: Option<Duration>,
+    pub idle: Option<Duration>,
+    pub write: Option<Duration>,
+    counts: [AtomicU64; 4]
+}
+
+impl Timeouts {
+    /// Count a timeout and log it.
+    pub fn hit(&self, kind: Timeout) {
+        let count = self.counts[kind as usize].fetch_add(1, atomic::Ordering::Relaxed) + 1;
+
+        match kind {
+            Timeout::Handshake => info!(count, "timeout/handshake"),
+            Timeout::Header => info!(count, "timeout/header"),
+            Timeout::Idle => info!(count, "timeout/idle"),
+            Timeout::Write => info!(count, "timeout/write")
+        }
+    }
+
+    /// Whether a connection failed on a timeout, counting the header timeouts hyper enforces.
+    pub fn check(&self, err: &(dyn Error + 'static)) -> bool {
+        if is_timeout(err) {
+            true
+        } else if err.downcast_ref::<hyper::Error>().is_some_and(hyper::Error::is_timeout) {
+            self.hit(Timeout::Header);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn count(&self, kind: Timeout) -> u64 {
+        self.counts[kind as usize].load(atomic::Ordering::Relaxed)
+    }
+}
+
+/// The responses a connection is still working on, it isn't idle before they are done.
+#[derive(Default)]
+pub struct InFlight {
+    count: AtomicUsize,
+    waker: AtomicWaker
+}
+
+impl InFlight {
+    pub fn start(self: &Arc<Self>) -> Responding {
+        self.count.fetch_add(1, atomic::Ordering::AcqRel);
+        Responding(self.clone())
+    }
+
+    fn is_busy(&self) -> bool {
+        self.count.load(atomic::Ordering::Acquire) > 0
+    }
+}
+
+/// A response in flight, until its body is dropped.
+pub struct Responding(Arc<InFlight>);
+
+impl Drop for Responding {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, atomic::Ordering::AcqRel) == 1 {
+            self.0.waker.wake();
+        }
+    }
+}
+
+/// Fail a connection that goes idle or stops taking writes.
+pub struct TimeoutStream<IO> {
+    io: IO,
+    timeouts: Arc<Timeouts>,
+    in_flight: Option<Arc<InFlight>>,
+    last_active: Instant,
+    idle: Option<Pin<Box<Sleep>>>,
+    write: Option<Pin<Box<Sleep>>>
+}
+
+impl<IO> TimeoutStream<IO> {
+    pub fn new(io: IO, timeouts: Arc<Timeouts>) -> TimeoutStream<IO> {
+        TimeoutStream { io, timeouts, in_flight: None, last_active: Instant::now(), idle: None, write: None }
+    }
+
+    /// Only count idle time while none of `in_flight` is.
+    ///
+    /// Without it, a response slower than the idle timeout fails.
+    pub fn in_flight(mut self, in_flight: Arc<InFlight>) -> TimeoutStream<IO> {
+        self.in_flight = Some(in_flight);
+        self
+    }
+
+    fn poll_idle(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        // a blocked write is for the write timeout to judge
+        let idle = match self.timeouts.idle {
+            Some(idle) if self.write.is_none() => idle,
+            _ => return Poll::Pending
+        };
+
+        // a slow response is for the write timeout and throttle to judge,
+        // the clock starts again once the last one is done
+        if let Some(in_flight) = self.in_flight.as_ref() {
+            in_flight.waker.register(cx.waker());
+            if in_flight.is_busy() {
+                self.last_active = Instant::now();
+                self.idle = None;
+                return Poll::Pending;
+            }
+        }
+
+        let deadline = self.last_active + idle;
+        let sleep = self.idle.get_or_insert_with(|| Box::pin(time::sleep_until(deadline)));
+        if sleep.deadline() != deadline {
+            sleep.as_mut().reset(deadline);
+        }
+
+        match sleep.as_mut().poll(cx) {
+            Poll::Ready(()) => {
+                self.timeouts.hit(Timeout::Idle);
+                Poll::Ready(timed_out(Timeout::Idle))
+            },
+            Poll::Pending => Poll::Pending
+        }
+    }
+
+    fn poll_stall<T>(&mut self, cx: &mut Context<'_>, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        match poll {
+            Poll::Ready(result) => {
+                self.write = None;
+                self.last_active = Instant::now();
+                Poll::Ready(result)
+            },
+            Poll::Pending => {
+                let write = match self.timeouts.write {
+                    Some(write) => write,
+                    None => return Poll::Pending
+                };
+                let sleep = self.write.get_or_insert_with(|| Box::pin(time::sleep(write)));
+
+                match sleep.as_mut().poll(cx) {
+                    Poll::Ready(()) => {
+                        self.timeouts.hit(Timeout::Write);
+                        Poll::Ready(Err(timed_out(Timeout::Write)))
+                    },
+                    Poll::Pending => Poll::Pending
+                }
+            }
+        }
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for TimeoutStream<IO> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        match Pin::new(&mut this.io).poll_read(cx, buf) {
+            Poll::Ready(result) => {
+                this.last_active = Instant::now();
+                Poll::Ready(result)
+            },
+            Poll::Pending => this.poll_idle(cx).map(Err)
+        }
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for TimeoutStream<IO> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.io).poll_write(cx, buf);
+        this.poll_stall(cx, poll)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.io).poll_flush(cx);
+        this.poll_stall(cx, poll)
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[IoSlice<'_>])
+        -> Poll<io::Result<usize>>
+    {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.io).poll_write_vectored(cx, bufs);
+        this.poll_stall(cx, poll)
+    }
+
+    #[inline]
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+}
+
+impl<IO: AsyncSendfile + Unpin> AsyncSendfile for TimeoutStream<IO> {
+    fn poll_sendfile(self: Pin<&mut Self>, cx: &mut Context<'_>, file: &fs::File, offset: u64, len: usize)
+        -> Poll<io::Result<usize>>
+    {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.io).poll_sendfile(cx, file, offset, len);
+        this.poll_stall(cx, poll)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl<IO: crate::ktls::KernelSocket> crate::ktls::KernelSocket for TimeoutStream<IO> {
+    #[inline]
+    fn kernel_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        self.io.kernel_fd()
+    }
+}
+
+/// Whether `err`, or what caused it, is a timeout counted already.
+fn is_timeout(err: &(dyn Error + 'static)) -> bool {
+    let mut source = Some(err);
+
+    while let Some(err) = source {
+        let inner = err.downcast_ref::<io::Error>().and_then(io::Error::get_ref);
+        if err.is::<TimedOut>() || inner.is_some_and(|err| err.is::<TimedOut>()) {
+            return true;
+        }
+        source = err.source();
+    }
+
+    false
+}
@@ -0,0 +1,301 @@
+use std::fs;
+use std::sync::{ Arc, RwLock };
+use std::str::FromStr;
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::future::Future;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use std::collections::HashMap;
+use anyhow::Context;
+use tokio::time;
+use tokio::task::block_in_place;
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType,
+    Identifier, NewAccount, NewOrder, OrderStatus, RetryPolicy
+};
+use rcgen::{ CertificateParams, CustomExtension, DnType, KeyPair };
+use ring::digest;
+use data_encoding::HEXLOWER;
+use tokio_rustls::rustls::crypto::ring as provider;
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::pki_types::{ PrivateKeyDer, PrivatePkcs8KeyDer };
+use x509_parser::prelude::{ FromDer, X509Certificate };
+use super::load_certified_key;
+use super::resolver::{ Resolver, Managed };
+use super::selfsigned::{ self_signed, names_set, subject_alt_names, write_private };
+
+
+/// The ALPN protocol of TLS-ALPN-01 validation handshakes.
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+const ACCOUNT_FILE: &str = "account.json";
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+const RETRY_DELAY: Duration = Duration::from_secs(10 * 60);
+const MAX_SLEEP: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How the ACME server validates that we control the names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum AcmeChallenge {
+    /// answered by the HTTP server under `/.well-known/acme-challenge/`.
+    Http01,
+
+    /// answered during the TLS handshake.
+    #[default]
+    TlsAlpn01
+}
+
+impl FromStr for AcmeChallenge {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http-01" => Ok(AcmeChallenge::Http01),
+            "tls-alpn-01" => Ok(AcmeChallenge::TlsAlpn01),
+            _ => Err(format!("unknown acme challenge: {}", s))
+        }
+    }
+}
+
+/// Pending challenge responses, shared with whatever answers them.
+#[derive(Default)]
+pub struct Challenges {
+    http: RwLock<HashMap<String, String>>,
+    tls_alpn: RwLock<HashMap<String, Arc<CertifiedKey>>>
+}
+
+impl Challenges {
+    /// The key authorization for an HTTP-01 `token`.
+    pub fn http(&self, token: &str) -> Option<String> {
+        self.http.read().unwrap().get(token).cloned()
+    }
+
+    /// The validation certificate for a TLS-ALPN-01 handshake to `name`.
+    pub fn tls_alpn(&self, name: &str) -> Option<Arc<CertifiedKey>> {
+        self.tls_alpn.read().unwrap().get(&name.to_ascii_lowercase()).cloned()
+    }
+
+    fn clear(&self) {
+        self.http.write().unwrap().clear();
+        self.tls_alpn.write().unwrap().clear();
+    }
+}
+
+/// Issues and renews one certificate for `names` from an ACME directory.
+pub struct Acme {
+    pub names: Vec<String>,
+    pub directory: String,
+    pub contact: Vec<String>,
+
+    /// account and certificate cache, kept per directory.
+    pub cache: PathBuf,
+
+    /// extra root certificate trusted for the directory, such as Pebble's.
+    pub root: Option<PathBuf>,
+    pub challenge: AcmeChallenge,
+    pub challenges: Arc<Challenges>,
+
+    /// whether the operator agreed to the directory's terms of service, needed for a new account.
+    pub agree_tos: bool
+}
+
+impl Acme {
+    /// Register the certificate with `resolver` and return the task that keeps it issued.
+    ///
+    /// Until the first certificate is issued, a cached one or a self-signed placeholder is served.
+    pub fn start(self, resolver: &mut Resolver) -> anyhow::Result<impl Future<Output = ()>> {
+        let (key, renew) = match self.cached() {
+            Some(key) => {
+                let renew = renew_at(&key);
+                info!(dir = ?self.dir(), "acme/cached certificate");
+                (key, renew)
+            },
+            None => (self_signed(&self.names, None)?, None)
+        };
+
+        resolver.set_challenges(self.challenges.clone());
+        let managed = resolver.insert_managed(&self.names, key);
+
+        Ok(self.run(managed, renew))
+    }
+
+    async fn run(self, managed: Managed, mut renew: Option<SystemTime>) {
+        loop {
+            if let Some(wait) = renew.and_then(|at| at.duration_since(SystemTime::now()).ok()) {
+                time::sleep(wait.min(MAX_SLEEP)).await;
+                continue
+            }
+
+            info!(names = ?self.names, "acme/issue");
+
+            match self.issue().await {
+                Ok(key) => {
+                    renew = renew_at(&key);
+                    managed.set(key);
+                    info!(names = ?self.names, ?renew, "acme/issued");
+                },
+                Err(err) => {
+                    error!(?err, "acme/issue");
+                    time::sleep(RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    fn dir(&self) -> PathBuf {
+        let hash = digest::digest(&digest::SHA256, self.directory.as_bytes());
+        self.cache.join(HEXLOWER.encode(&hash.as_ref()[..8]))
+    }
+
+    fn cached(&self) -> Option<CertifiedKey> {
+        let dir = self.dir();
+        let (cert, key) = (dir.join(CERT_FILE), dir.join(KEY_FILE));
+
+        if !cert.is_file() || !key.is_file() {
+            return None;
+        }
+
+        match load_certified_key(&cert, &key, None) {
+            Ok(certified) => {
+                let names = certified.end_entity_cert().ok().map(subject_alt_names);
+                (names == Some(names_set(&self.names))).then_some(certified)
+            },
+            Err(err) => {
+                warn!(?err, ?dir, "acme/cache");
+                None
+            }
+        }
+    }
+
+    async fn account(&self) -> anyhow::Result<Account> {
+        let path = self.dir().join(ACCOUNT_FILE);
+        let builder = match self.root.as_ref() {
+            Some(root) => Account::builder_with_root(root)?,
+            None => Account::builder()?
+        };
+
+        let saved = block_in_place(|| match path.is_file() {
+            true => fs::read(&path).map(Some),
+            false => Ok(None)
+        })?;
+        if let Some(saved) = saved {
+            let credentials: AccountCredentials = serde_json::from_slice(&saved)
+                .with_context(|| format!("Bad acme account: {}", path.display()))?;
+            return Ok(builder.from_credentials(credentials).await?);
+        }
+
+        anyhow::ensure!(self.agree_tos, "a new acme account needs --acme-agree-tos");
+        let contact = self.contact.iter().map(String::as_str).collect::<Vec<_>>();
+        let new_account = NewAccount {
+            contact: &contact,
+            terms_of_service_agreed: self.agree_tos,
+            only_return_existing: false
+        };
+        let (account, credentials) = builder
+            .create(&new_account, self.directory.clone(), None)
+            .await?;
+
+        let credentials = serde_json::to_vec(&credentials)?;
+        block_in_place(|| {
+            fs::create_dir_all(self.dir())?;
+            write_private(&path, &credentials)
+        })
+            .with_context(|| format!("save acme account: {}", path.display()))?;
+        info!(id = %account.id(), "acme/account created");
+
+        Ok(account)
+    }
+
+    async fn issue(&self) -> anyhow::Result<CertifiedKey> {
+        let account = self.account().await?;
+        let result = self.order(&account).await;
+        self.challenges.clear();
+        let (chain, key) = result?;
+
+        let dir = self.dir();
+        block_in_place(|| {
+            fs::create_dir_all(&dir)?;
+            fs::write(dir.join(CERT_FILE), chain)?;
+            write_private(&dir.join(KEY_FILE), key.as_bytes())?;
+            load_certified_key(&dir.join(CERT_FILE), &dir.join(KEY_FILE), None)
+        })
+    }
+
+    /// Returns the certificate chain and private key, as PEM.
+    async fn order(&self, account: &Account) -> anyhow::Result<(String, String)> {
+        let identifiers = self.names.iter()
+            .map(|name| Identifier::Dns(name.clone()))
+            .collect::<Vec<_>>();
+        let mut order = account.new_order(&NewOrder::new(&identifiers)).await?;
+
+        let mut authorizations = order.authorizations();
+        while let Some(authz) = authorizations.next().await {
+            let mut authz = authz?;
+            match authz.status {
+                AuthorizationStatus::Pending => (),
+                AuthorizationStatus::Valid => continue,
+                status => anyhow::bail!("authorization is {:?}", status)
+            }
+
+            let ty = match self.challenge {
+                AcmeChallenge::Http01 => ChallengeType::Http01,
+                AcmeChallenge::TlsAlpn01 => ChallengeType::TlsAlpn01
+            };
+            let mut challenge = authz.challenge(ty.clone())
+                .with_context(|| format!("{:?} challenge not offered", ty))?;
+            let key_auth = challenge.key_authorization();
+
+            match self.challenge {
+                AcmeChallenge::Http01 => {
+                    self.challenges.http.write().unwrap()
+                        .insert(challenge.token.clone(), key_auth.as_str().into());
+                },
+                AcmeChallenge::TlsAlpn01 => {
+                    let name = challenge.identifier().to_string().to_ascii_lowercase();
+                    let cert = tls_alpn_cert(&name, key_auth.digest().as_ref())?;
+                    self.challenges.tls_alpn.write().unwrap().insert(name, Arc::new(cert));
+                }
+            }
+
+            debug!(name = %challenge.identifier(), "acme/challenge ready");
+            challenge.set_ready().await?;
+        }
+
+        let retry = RetryPolicy::new().timeout(Duration::from_secs(120));
+        let status = order.poll_ready(&retry).await?;
+        anyhow::ensure!(status == OrderStatus::Ready, "order is {:?}", status);
+
+        let key = order.finalize().await?;
+        let chain = order.poll_certificate(&retry).await?;
+
+        Ok((chain, key))
+    }
+}
+
+/// The self-signed certificate for a TLS-ALPN-01 validation, RFC 8737.
+fn tls_alpn_cert(name: &str, digest: &[u8]) -> anyhow::Result<CertifiedKey> {
+    let mut params = CertificateParams::new(vec![name.to_owned()])?;
+    params.distinguished_name.push(DnType::CommonName, name);
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest)];
+    let key_pair = KeyPair::generate()?;
+    let cert = params.self_signed(&key_pair)?;
+
+    let key = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
+    let key = provider::default_provider()
+        .key_provider
+        .load_private_key(key)?;
+
+    Ok(CertifiedKey::new(vec![cert.der().clone()], key))
+}
+
+/// Renew once two thirds of the lifetime have passed.
+fn renew_at(key: &CertifiedKey) -> Option<SystemTime> {
+    let cert = key.end_entity_cert().ok()?;
+    let (_, cert) = X509Certificate::from_der(cert.as_ref()).ok()?;
+    let validity = cert.validity();
+    let not_before = u64::try_from(validity.not_before.timestamp()).ok()?;
+    let not_after = u64::try_from(validity.not_after.timestamp()).ok()?;
+    let renew = not_after - not_after.saturating_sub(not_before) / 3;
+
+    Some(UNIX_EPOCH + Duration::from_secs(renew))
+}
@@ -0,0 +1,187 @@
+use std::{ fs, fmt };
+use std::sync::{ Arc, RwLock };
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::collections::HashMap;
+use tokio_rustls::rustls::server::{ ClientHello, ResolvesServerCert };
+use tokio_rustls::rustls::sign::CertifiedKey;
+use super::load_certified_key;
+use super::acme::{ Challenges, ACME_TLS_ALPN };
+
+
+/// Where a certificate was loaded from, so it can be loaded again.
+pub struct Source {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub passphrase: Option<Arc<[u8]>>
+}
+
+impl Source {
+    pub fn load(&self) -> anyhow::Result<CertifiedKey> {
+        load_certified_key(&self.cert, &self.key, self.passphrase.as_deref())
+    }
+
+    fn mtime(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let mtime = |path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        (mtime(&self.cert), mtime(&self.key))
+    }
+}
+
+struct Cert {
+    /// `None` for certificates that only live in memory.
+    source: Option<Source>,
+    mtime: (Option<SystemTime>, Option<SystemTime>),
+    key: Arc<CertifiedKey>
+}
+
+struct Slot(RwLock<Cert>);
+
+impl Slot {
+    fn new(source: Source) -> anyhow::Result<Slot> {
+        let mtime = source.mtime();
+        let key = Arc::new(source.load()?);
+        Ok(Slot(RwLock::new(Cert { source: Some(source), mtime, key })))
+    }
+
+    fn fixed(key: CertifiedKey) -> Slot {
+        Slot(RwLock::new(Cert { source: None, mtime: (None, None), key: Arc::new(key) }))
+    }
+
+    fn reload(&self, force: bool) -> anyhow::Result<bool> {
+        let (mtime, key) = {
+            let cert = self.0.read().unwrap();
+            let source = match cert.source.as_ref() {
+                Some(source) => source,
+                None => return Ok(false)
+            };
+            let mtime = source.mtime();
+            if !force && mtime == cert.mtime {
+                return Ok(false);
+            }
+            (mtime, source.load())
+        };
+
+        // don't retry a broken certificate until its files change again
+        let mut cert = self.0.write().unwrap();
+        cert.mtime = mtime;
+        cert.key = Arc::new(key?);
+
+        Ok(true)
+    }
+
+    fn key(&self) -> Arc<CertifiedKey> {
+        self.0.read().unwrap().key.clone()
+    }
+}
+
+/// A certificate that is replaced from outside the resolver, such as one issued by ACME.
+#[derive(Clone)]
+pub struct Managed(Arc<Slot>);
+
+impl Managed {
+    pub fn set(&self, key: CertifiedKey) {
+        self.0.0.write().unwrap().key = Arc::new(key);
+    }
+}
+
+/// Picks the server certificate by SNI, falling back to the default one.
+///
+/// Certificates can be swapped at any time, a handshake picks up whatever
+/// certificate is current when it starts, established connections are not affected.
+#[derive(Default)]
+pub struct Resolver {
+    default: Option<Arc<Slot>>,
+    names: HashMap<String, Arc<Slot>>,
+    challenges: Option<Arc<Challenges>>
+}
+
+impl Resolver {
+    pub fn set_default(&mut self, source: Source) -> anyhow::Result<()> {
+        self.default = Some(Arc::new(Slot::new(source)?));
+        Ok(())
+    }
+
+    /// Use a certificate that is never reloaded as the default one.
+    pub fn set_default_key(&mut self, key: CertifiedKey) {
+        self.default = Some(Arc::new(Slot::fixed(key)));
+    }
+
+    /// `name` may be a wildcard such as `*.example.com`.
+    pub fn insert(&mut self, name: &str, source: Source) -> anyhow::Result<()> {
+        self.names.insert(name.to_ascii_lowercase(), Arc::new(Slot::new(source)?));
+        Ok(())
+    }
+
+    /// Serve `key` for all of `names`, and as the default if there is none yet.
+    pub fn insert_managed(&mut self, names: &[String], key: CertifiedKey) -> Managed {
+        let slot = Arc::new(Slot::fixed(key));
+
+        for name in names {
+            self.names.insert(name.to_ascii_lowercase(), slot.clone());
+        }
+        if self.default.is_none() {
+            self.default = Some(slot.clone());
+        }
+
+        Managed(slot)
+    }
+
+    /// Answer TLS-ALPN-01 handshakes with the pending challenge certificates.
+    pub fn set_challenges(&mut self, challenges: Arc<Challenges>) {
+        self.challenges = Some(challenges);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.names.is_empty()
+    }
+
+    /// Reload the certificates whose files changed since the last load, or all if `force`.
+    ///
+    /// On failure the current certificate is kept.
+    pub fn reload(&self, force: bool) {
+        let slots = self.default.iter()
+            .map(|slot| ("<default>", slot))
+            .chain(self.names.iter().map(|(name, slot)| (name.as_str(), slot)));
+
+        for (name, slot) in slots {
+            match slot.reload(force) {
+                Ok(true) => info!(%name, "tls/reload: certificate reloaded"),
+                Ok(false) => (),
+                Err(err) => error!(%name, ?err, "tls/reload")
+            }
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Arc<Slot>> {
+        let name = name.to_ascii_lowercase();
+
+        self.names.get(&name)
+            .or_else(|| {
+                let (_, parent) = name.split_once('.')?;
+                self.names.get(&format!("*.{}", parent))
+            })
+    }
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        if let Some(challenges) = self.challenges.as_ref() {
+            if hello.alpn().is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN)) {
+                return hello.server_name().and_then(|name| challenges.tls_alpn(name));
+            }
+        }
+
+        hello.server_name()
+            .and_then(|name| self.lookup(name))
+            .or(self.default.as_ref())
+            .map(|slot| slot.key())
+    }
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Resolver")
+            .field("names", &self.names.keys())
+            .finish_non_exhaustive()
+    }
+}
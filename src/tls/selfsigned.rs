@@ -0,0 +1,115 @@
+use std::{ fs, io };
+use std::io::Write;
+use std::path::Path;
+use std::net::IpAddr;
+use std::convert::TryFrom;
+use std::collections::BTreeSet;
+use anyhow::Context;
+use rcgen::{ CertificateParams, DnType, KeyPair };
+use ring::digest;
+use data_encoding::HEXUPPER;
+use tokio_rustls::rustls::crypto::ring as provider;
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::pki_types::{ CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer };
+use x509_parser::prelude::{ FromDer, X509Certificate, GeneralName };
+use super::load_certified_key;
+
+
+const CERT_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+
+/// Generate a self-signed certificate for `names`, which may be DNS names or IP addresses.
+///
+/// With `dir`, the certificate is saved there and reused as long as it covers the same names.
+pub fn self_signed(names: &[String], dir: Option<&Path>) -> anyhow::Result<CertifiedKey> {
+    if let Some(dir) = dir {
+        let (cert, key) = (dir.join(CERT_FILE), dir.join(KEY_FILE));
+
+        if cert.is_file() && key.is_file() {
+            let certified = load_certified_key(&cert, &key, None)?;
+
+            if subject_alt_names(certified.end_entity_cert()?) == names_set(names) {
+                info!(?dir, "self-signed/reuse");
+                return Ok(certified);
+            }
+
+            info!(?dir, "self-signed/names changed, regenerate");
+        }
+    }
+
+    let mut params = CertificateParams::new(names.to_vec())?;
+    params.distinguished_name.push(DnType::CommonName, "webdir self-signed");
+    let key_pair = KeyPair::generate()?;
+    let cert = params.self_signed(&key_pair)?;
+
+    if let Some(dir) = dir {
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join(CERT_FILE), cert.pem())?;
+        write_private(&dir.join(KEY_FILE), key_pair.serialize_pem().as_bytes())
+            .with_context(|| format!("save self-signed key: {}", dir.display()))?;
+    }
+
+    let key = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(key_pair.serialize_der()));
+    let key = provider::default_provider()
+        .key_provider
+        .load_private_key(key)?;
+
+    Ok(CertifiedKey::new(vec![cert.der().clone()], key))
+}
+
+/// SHA-256 fingerprint of a certificate, as colon separated hex.
+pub fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    let hash = digest::digest(&digest::SHA256, cert.as_ref());
+    let hex = HEXUPPER.encode(hash.as_ref());
+
+    hex.as_bytes()
+        .chunks(2)
+        .map(|byte| String::from_utf8_lossy(byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+pub(super) fn names_set(names: &[String]) -> BTreeSet<String> {
+    names.iter()
+        .map(|name| match name.parse::<IpAddr>() {
+            Ok(ip) => ip.to_string(),
+            Err(_) => name.to_ascii_lowercase()
+        })
+        .collect()
+}
+
+pub(super) fn subject_alt_names(cert: &CertificateDer<'_>) -> BTreeSet<String> {
+    let mut names = BTreeSet::new();
+
+    if let Ok((_, cert)) = X509Certificate::from_der(cert.as_ref()) {
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in &san.value.general_names {
+                match *name {
+                    GeneralName::DNSName(name) => {
+                        names.insert(name.to_ascii_lowercase());
+                    },
+                    GeneralName::IPAddress(ip) => if let Ok(ip) = <[u8; 4]>::try_from(ip) {
+                        names.insert(IpAddr::from(ip).to_string());
+                    } else if let Ok(ip) = <[u8; 16]>::try_from(ip) {
+                        names.insert(IpAddr::from(ip).to_string());
+                    },
+                    _ => ()
+                }
+            }
+        }
+    }
+
+    names
+}
+
+pub(super) fn write_private(path: &Path, buf: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+
+    #[cfg(unix)] {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(path)?.write_all(buf)
+}
@@ -0,0 +1,94 @@
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ self, AtomicU64 };
+use std::path::{ Path, PathBuf };
+use std::collections::HashMap;
+use tokio::sync::Notify;
+
+
+/// Response bodies still being sent, so shutdown can wait for them.
+#[derive(Default)]
+pub struct Transfers {
+    next: AtomicU64,
+    active: Mutex<HashMap<u64, Arc<State>>>,
+    idle: Notify
+}
+
+struct State {
+    path: PathBuf,
+    sent: AtomicU64
+}
+
+/// A running transfer, finished when dropped.
+pub struct Transfer {
+    transfers: Arc<Transfers>,
+    id: u64,
+    state: Arc<State>
+}
+
+/// What was left of a transfer when it was aborted.
+#[derive(Debug)]
+pub struct Aborted {
+    pub path: PathBuf,
+    pub sent: u64
+}
+
+impl Transfers {
+    pub fn start(self: &Arc<Self>, path: &Path) -> Transfer {
+        let id = self.next.fetch_add(1, atomic::Ordering::Relaxed);
+        let state = Arc::new(State { path: path.to_path_buf(), sent: AtomicU64::new(0) });
+        self.active.lock().unwrap().insert(id, state.clone());
+
+        Transfer { transfers: self.clone(), id, state }
+    }
+
+    pub fn len(&self) -> usize {
+        self.active.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Wait until no transfer is running.
+    pub async fn drained(&self) {
+        loop {
+            let notified = self.idle.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if self.is_empty() {
+                return
+            }
+
+            notified.await;
+        }
+    }
+
+    /// The transfers that are still running.
+    pub fn active(&self) -> Vec<Aborted> {
+        self.active.lock().unwrap()
+            .values()
+            .map(|state| Aborted {
+                path: state.path.clone(),
+                sent: state.sent.load(atomic::Ordering::Relaxed)
+            })
+            .collect()
+    }
+}
+
+impl Transfer {
+    pub fn sent(&self, len: usize) {
+        self.state.sent.fetch_add(len as u64, atomic::Ordering::Relaxed);
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        let mut active = self.transfers.active.lock().unwrap();
+        active.remove(&self.id);
+
+        if active.is_empty() {
+            self.transfers.idle.notify_waiters();
+        }
+    }
+}
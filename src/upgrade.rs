@@ -0,0 +1,148 @@
+//! Hand the listening sockets over to a new process, for upgrades without downtime.
+//!
+//! The new process finds its sockets in `WEBDIR_UPGRADE_FDS`, as `<fd>=<name>` separated by `,`,
+//! and writes a byte to the pipe in `WEBDIR_UPGRADE_READY` once it serves them.
+
+use std::{ env, io };
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+use std::os::unix::io::{ AsRawFd, FromRawFd, OwnedFd, RawFd };
+use std::os::unix::process::CommandExt;
+use tokio::time;
+use tokio::io::AsyncReadExt;
+use tokio::task::block_in_place;
+use crate::listener::Listener;
+
+
+const FDS_ENV: &str = "WEBDIR_UPGRADE_FDS";
+const READY_ENV: &str = "WEBDIR_UPGRADE_READY";
+
+/// How long the new process may take to serve the sockets.
+pub const READY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Tells the old process that we took over.
+pub struct Ready(OwnedFd);
+
+impl Ready {
+    pub fn done(self) -> io::Result<()> {
+        let ret = unsafe { libc::write(self.0.as_raw_fd(), b"1".as_ptr().cast(), 1) };
+        if ret == 1 { Ok(()) } else { Err(io::Error::last_os_error()) }
+    }
+}
+
+/// What an upgrade handed us in the environment.
+pub struct UpgradeEnv {
+    fds: Option<String>,
+    ready: Option<String>
+}
+
+impl UpgradeEnv {
+    /// Read and remove the variables, so a later upgrade starts clean.
+    ///
+    /// Changing the environment races with other threads, so call it before the runtime starts.
+    pub fn take() -> UpgradeEnv {
+        let env = UpgradeEnv {
+            fds: env::var(FDS_ENV).ok(),
+            ready: env::var(READY_ENV).ok()
+        };
+        env::remove_var(FDS_ENV);
+        env::remove_var(READY_ENV);
+        env
+    }
+
+    /// The sockets handed over by the old process, if we were started by an upgrade.
+    pub fn inherited(&mut self) -> io::Result<Vec<(String, Listener)>> {
+        let mut listeners = Vec::new();
+        for item in self.fds.take().iter().flat_map(|fds| fds.split(',')).filter(|item| !item.is_empty()) {
+            let (fd, name) = item.split_once('=').unwrap_or((item, "upgrade"));
+            let fd = parse_fd(fd)?;
+
+            // the old process passed this fd to us alone
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            listeners.push((name.to_owned(), Listener::from_fd(fd)?));
+        }
+
+        Ok(listeners)
+    }
+
+    /// How to tell the old process that we took over, if we were started by an upgrade.
+    pub fn ready(&mut self) -> io::Result<Option<Ready>> {
+        match self.ready.take() {
+            Some(fd) => Ok(Some(Ready(unsafe { OwnedFd::from_raw_fd(parse_fd(&fd)?) }))),
+            None => Ok(None)
+        }
+    }
+}
+
+/// Start `exe` with the same arguments and the `listeners` fds, and wait until it serves them.
+///
+/// A new process that isn't ready within [`READY_TIMEOUT`] is killed.
+/// Returns the pid of the new process.
+pub async fn spawn(exe: &Path, listeners: &[(RawFd, String)]) -> io::Result<u32> {
+    let (read, write) = pipe()?;
+    let fds = listeners.iter()
+        .map(|(fd, name)| format!("{}={}", fd, name))
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut inherit = listeners.iter().map(|(fd, _)| *fd).collect::<Vec<_>>();
+    inherit.push(write.as_raw_fd());
+
+    let mut command = Command::new(exe);
+    command
+        .args(env::args_os().skip(1).collect::<Vec<OsString>>())
+        .env(FDS_ENV, fds)
+        .env(READY_ENV, write.as_raw_fd().to_string());
+
+    // only async-signal-safe calls between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            for &fd in &inherit {
+                if libc::fcntl(fd, libc::F_SETFD, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+
+    let mut child = command.spawn()?;
+    drop(write);
+
+    // EOF means the new process exited before it was ready
+    let mut buf = [0; 1];
+    let mut read = tokio::fs::File::from_std(std::fs::File::from(read));
+    let err = match time::timeout(READY_TIMEOUT, read.read(&mut buf)).await {
+        Ok(Ok(1)) => return Ok(child.id()),
+        Ok(Ok(_)) => io::Error::other("new process exited before it was ready"),
+        Ok(Err(err)) => err,
+        Err(_) => io::Error::new(io::ErrorKind::TimedOut, "new process wasn't ready in time")
+    };
+
+    // it may still run, and it serves our sockets
+    let _ = child.kill();
+    block_in_place(|| child.wait())?;
+    Err(err)
+}
+
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let pair = unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+    for fd in fds {
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(pair)
+}
+
+fn parse_fd(fd: &str) -> io::Result<RawFd> {
+    fd.parse()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, format!("bad upgrade fd {}: {}", fd, err)))
+}
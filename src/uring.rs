@@ -0,0 +1,273 @@
+//! File I/O through io_uring, so a transfer waits on the kernel instead of holding a runtime worker.
+//!
+//! One ring serves the whole process. Operations are submitted as soon as they are
+//! polled, and a task reaps completions whenever the ring's eventfd fires.
+
+use std::{ io, mem };
+use std::any::Any;
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::path::Path;
+use std::sync::{ Arc, Mutex, OnceLock };
+use std::time::{ Duration, SystemTime };
+use std::collections::HashMap;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{ AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd };
+use bytes::Bytes;
+use io_uring::{ IoUring, opcode, squeue, types };
+use tokio::io::unix::AsyncFd;
+use tokio::sync::oneshot;
+use crate::utils::Kind;
+
+
+const ENTRIES: u32 = 256;
+const CHUNK: usize = 1 << 16;
+
+static RING: OnceLock<Option<&'static Ring>> = OnceLock::new();
+
+/// The shared ring, `None` if the kernel wouldn't give us one.
+pub fn ring() -> Option<&'static Ring> {
+    *RING.get_or_init(|| match Ring::new() {
+        Ok(ring) => {
+            let ring: &'static Ring = Box::leak(Box::new(ring));
+            tokio::spawn(ring.reap());
+            Some(ring)
+        },
+        Err(err) => {
+            warn!(?err, "uring/unsupported");
+            None
+        }
+    })
+}
+
+struct Pending {
+    tx: oneshot::Sender<(i32, Box<dyn Any + Send>)>,
+
+    /// what the kernel reads or writes, kept alive until it is done.
+    data: Box<dyn Any + Send>,
+
+    /// the result is a new fd, closed if nobody waits for it anymore.
+    opens: bool
+}
+
+struct State {
+    ring: IoUring,
+    pending: HashMap<u64, Pending>,
+    next: u64
+}
+
+pub struct Ring {
+    state: Mutex<State>,
+    eventfd: AsyncFd<OwnedFd>
+}
+
+impl Ring {
+    fn new() -> io::Result<Ring> {
+        let ring = IoUring::new(ENTRIES)?;
+
+        let eventfd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if eventfd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let eventfd = unsafe { OwnedFd::from_raw_fd(eventfd) };
+        ring.submitter().register_eventfd(eventfd.as_raw_fd())?;
+
+        Ok(Ring {
+            state: Mutex::new(State { ring, pending: HashMap::new(), next: 0 }),
+            eventfd: AsyncFd::new(eventfd)?
+        })
+    }
+
+    async fn reap(&'static self) {
+        loop {
+            let mut guard = match self.eventfd.readable().await {
+                Ok(guard) => guard,
+                Err(err) => {
+                    error!(?err, "uring/reap");
+                    return;
+                }
+            };
+
+            // reset the counter, readiness is cleared once there is nothing to read
+            let _ = guard.try_io(|eventfd| {
+                let mut count = [0u8; 8];
+                match unsafe { libc::read(eventfd.as_raw_fd(), count.as_mut_ptr() as *mut _, count.len()) } {
+                    n if n < 0 => Err(io::Error::last_os_error()),
+                    _ => Ok(())
+                }
+            });
+
+            let done = {
+                let mut state = self.state.lock().unwrap();
+                let State { ring, pending, .. } = &mut *state;
+                let done = ring.completion()
+                    .filter_map(|cqe| Some((pending.remove(&cqe.user_data())?, cqe.result())))
+                    .collect::<Vec<_>>();
+
+                // whatever a full completion queue held back
+                let _ = ring.submit();
+                done
+            };
+
+            for (op, result) in done {
+                if let Err((fd, _)) = op.tx.send((result, op.data)) {
+                    if op.opens && fd >= 0 {
+                        unsafe { libc::close(fd) };
+                    }
+                }
+            }
+        }
+    }
+
+    /// Submit `entry`, which may only point into heap memory owned by `data`.
+    ///
+    /// Dropping the future doesn't cancel the operation, `data` lives until it completes.
+    async unsafe fn run<T: Any + Send>(&self, entry: squeue::Entry, data: T, opens: bool) -> io::Result<(u32, T)> {
+        let (tx, rx) = oneshot::channel();
+
+        {
+            let mut state = self.state.lock().unwrap();
+            let State { ring, pending, next } = &mut *state;
+            let key = *next;
+            *next += 1;
+
+            let entry = entry.user_data(key);
+            if ring.submission().push(&entry).is_err() {
+                ring.submit()?;
+                if ring.submission().push(&entry).is_err() {
+                    return Err(io::Error::new(io::ErrorKind::WouldBlock, "io_uring submission queue full"));
+                }
+            }
+            pending.insert(key, Pending { tx, data: Box::new(data), opens });
+
+            match ring.submit() {
+                // left in the queue for the reaper
+                Err(err) if matches!(err.raw_os_error(), Some(libc::EBUSY | libc::EAGAIN)) => (),
+                Err(err) => return Err(err),
+                Ok(_) => ()
+            }
+        }
+
+        let (result, data) = rx.await
+            .map_err(|_| io::Error::other("io_uring reaper gone"))?;
+        let data = *data.downcast::<T>()
+            .map_err(|_| io::Error::other("io_uring data mixed up"))?;
+
+        match result {
+            n if n < 0 => Err(io::Error::from_raw_os_error(-n)),
+            n => Ok((n as u32, data))
+        }
+    }
+
+    pub async fn open(&'static self, path: &Path) -> io::Result<File> {
+        let path = cstring(path)?;
+        let entry = opcode::OpenAt::new(types::Fd(libc::AT_FDCWD), path.as_ptr())
+            .flags(libc::O_RDONLY | libc::O_CLOEXEC)
+            .build();
+        let (fd, _) = unsafe { self.run(entry, path, true).await? };
+
+        Ok(File {
+            ring: self,
+            fd: Arc::new(unsafe { OwnedFd::from_raw_fd(fd as RawFd) }),
+            pos: 0
+        })
+    }
+
+    /// `statx(2)` of `path`, of what it points to if `follow`.
+    pub async fn statx(&self, path: &Path, follow: bool) -> io::Result<Stat> {
+        let flags = if follow { 0 } else { libc::AT_SYMLINK_NOFOLLOW };
+        self.statx_at(libc::AT_FDCWD, cstring(path)?, flags, None).await
+    }
+
+    async fn statx_at(&self, dirfd: RawFd, path: CString, flags: i32, keep: Option<Arc<OwnedFd>>) -> io::Result<Stat> {
+        let mut buf: Box<libc::statx> = Box::new(unsafe { mem::zeroed() });
+        let entry = opcode::Statx::new(
+            types::Fd(dirfd),
+            path.as_ptr(),
+            &mut *buf as *mut libc::statx as *mut types::statx
+        )
+            .flags(flags)
+            .mask(libc::STATX_TYPE | libc::STATX_MODE | libc::STATX_SIZE | libc::STATX_MTIME)
+            .build();
+        let (_, (buf, ..)) = unsafe { self.run(entry, (buf, path, keep), false).await? };
+
+        Ok(Stat::from(&*buf))
+    }
+}
+
+fn cstring(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "nul byte in path"))
+}
+
+/// What `statx(2)` tells us about a path.
+pub struct Stat {
+    pub kind: Kind,
+    pub len: u64,
+    pub modified: Option<SystemTime>
+}
+
+impl From<&libc::statx> for Stat {
+    fn from(stx: &libc::statx) -> Stat {
+        let kind = match u32::from(stx.stx_mode) & libc::S_IFMT {
+            libc::S_IFREG => Kind::File,
+            libc::S_IFDIR => Kind::Dir,
+            _ => Kind::Special
+        };
+        let modified = u64::try_from(stx.stx_mtime.tv_sec).ok()
+            .and_then(|secs| SystemTime::UNIX_EPOCH.checked_add(Duration::new(secs, stx.stx_mtime.tv_nsec)));
+
+        Stat {
+            kind,
+            len: stx.stx_size,
+            modified
+        }
+    }
+}
+
+/// A file read at offsets we keep ourselves.
+pub struct File {
+    ring: &'static Ring,
+    fd: Arc<OwnedFd>,
+    pos: u64
+}
+
+impl File {
+    pub async fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            io::SeekFrom::Start(pos) => Some(pos),
+            io::SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+            io::SeekFrom::End(offset) => {
+                let empty = CString::default();
+                let fd = self.fd.as_raw_fd();
+                let stat = self.ring.statx_at(fd, empty, libc::AT_EMPTY_PATH, Some(self.fd.clone())).await?;
+                stat.len.checked_add_signed(offset)
+            }
+        };
+
+        self.pos = pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start"))?;
+        Ok(self.pos)
+    }
+
+    pub async fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        let mut buf = Vec::<u8>::with_capacity(CHUNK);
+        let entry = opcode::Read::new(types::Fd(self.fd.as_raw_fd()), buf.as_mut_ptr(), CHUNK as u32)
+            .offset(self.pos)
+            .build();
+        let (n, (mut buf, _)) = unsafe { self.ring.run(entry, (buf, self.fd.clone()), false).await? };
+
+        if n == 0 {
+            return Ok(None);
+        }
+
+        unsafe { buf.set_len(n as usize) };
+        self.pos += u64::from(n);
+        Ok(Some(Bytes::from(buf)))
+    }
+}
+
+impl AsFd for File {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
@@ -8,6 +8,7 @@ use siphasher::sip::SipHasher;
 use percent_encoding::{ NON_ALPHANUMERIC, percent_encode, percent_decode };
 use maud::{ html, Markup };
 use crate::file::File;
+use crate::symlink::SymlinkPolicy;
 
 
 pub fn html_utf8() -> headers::ContentType {
@@ -21,6 +22,29 @@ pub fn err_html(display: fmt::Arguments) -> Markup {
     }
 }
 
+/// What a path is, as far as serving it goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Dir,
+
+    /// FIFOs, sockets and device nodes, which are never served.
+    Special
+}
+
+impl Kind {
+    pub fn of(metadata: &fs::Metadata) -> Kind {
+        let ty = metadata.file_type();
+        if ty.is_file() {
+            Kind::File
+        } else if ty.is_dir() {
+            Kind::Dir
+        } else {
+            Kind::Special
+        }
+    }
+}
+
 pub fn path_canonicalize<P: AsRef<Path>>(root: &Path, path: P) -> (usize, PathBuf) {
     path.as_ref()
         .components()
@@ -30,7 +54,7 @@ pub fn path_canonicalize<P: AsRef<Path>>(root: &Path, path: P) -> (usize, PathBu
                     sum.push(p);
                     depth += 1;
                 },
-                Component::ParentDir if depth > 0 => if sum.pop() {
+                Component::ParentDir if depth > 0 && sum.pop() => {
                     depth -= 1;
                 },
                 _ => ()
@@ -40,6 +64,18 @@ pub fn path_canonicalize<P: AsRef<Path>>(root: &Path, path: P) -> (usize, PathBu
 }
 
 
+/// Where the decoded request `path` really leads, as an absolute path below `root`.
+///
+/// Lexical if it doesn't exist, follows the symlink policy, or leads out of `root`.
+pub fn resolve_path(root: &Path, policy: SymlinkPolicy, path: &Path) -> PathBuf {
+    let (_, target) = path_canonicalize(root, path);
+
+    policy.check(root, &target).ok()
+        .and_then(|_| target.canonicalize().ok())
+        .and_then(|target| Some(Path::new("/").join(target.strip_prefix(root).ok()?)))
+        .unwrap_or_else(|| path_canonicalize(Path::new("/"), path).1)
+}
+
 #[cfg(unix)]
 #[inline]
 pub fn encode_path(name: &OsStr) -> String {
@@ -52,6 +88,19 @@ pub fn encode_path(name: &OsStr) -> String {
         .fold(init, Add::add)
 }
 
+/// A path relative to the root as an absolute URL path below `base`, ending in `/`.
+pub fn encode_dir_url(base: &str, path: &Path) -> String {
+    let mut url = format!("{}/", base);
+
+    for name in path.iter() {
+        let name = encode_path(name);
+        url.push_str(&name[2..]);
+        url.push('/');
+    }
+
+    url
+}
+
 #[cfg(not(unix))]
 #[inline]
 pub fn encode_path(name: &OsStr) -> String {